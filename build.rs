@@ -1,7 +1,12 @@
 use std::env;
-use std::path::PathBuf;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
 
 fn main() {
+    if env::var("CARGO_FEATURE_EMBED_ASSETS").is_ok() {
+        embed_assets();
+    }
+
     let target = env::var("TARGET").unwrap();
     if target.contains("pc-windows") {
         let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
@@ -39,4 +44,68 @@ fn main() {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+fn read_json(path: &Path) -> serde_json::Value {
+    let file = std::fs::File::open(path)
+        .unwrap_or_else(|e| panic!("Can't open {}: {}", path.display(), e));
+    serde_json::from_reader(file).unwrap_or_else(|e| panic!("Can't parse {}: {}", path.display(), e))
+}
+
+fn json_str<'a>(value: &'a serde_json::Value, key: &str) -> Option<&'a str> {
+    value.get(key).and_then(|v| v.as_str())
+}
+
+// Writes $OUT_DIR/embedded_assets.rs, which include_bytes!s the manifest and
+// every file it names. The project defaults to the crate root and can be
+// moved with BBMICRO_PROJECT.
+fn embed_assets() {
+    println!("cargo:rerun-if-env-changed=BBMICRO_PROJECT");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let project_dir = match env::var("BBMICRO_PROJECT") {
+        Ok(dir) => manifest_dir.join(dir),
+        Err(_) => manifest_dir,
+    };
+    let manifest_path = project_dir.join("bbmicro.json");
+    println!("cargo:rerun-if-changed={}", manifest_path.display());
+
+    let manifest = read_json(&manifest_path);
+    let asset_root = project_dir.join(json_str(&manifest, "asset_root").unwrap_or("."));
+
+    let mut names: Vec<String> = Vec::new();
+    for key in ["sprites", "flags", "pico8", "audio"].iter() {
+        names.extend(json_str(&manifest, key).map(String::from));
+    }
+    if let Some(font) = manifest.get("font") {
+        for key in ["image", "glyphs"].iter() {
+            names.extend(json_str(font, key).map(String::from));
+        }
+    }
+    if let Some(maps) = manifest.get("maps").and_then(|v| v.as_array()) {
+        names.extend(maps.iter().filter_map(|v| v.as_str()).map(String::from));
+    }
+    if let Some(audio) = json_str(&manifest, "audio") {
+        let audio_config = read_json(&asset_root.join(audio));
+        for section in ["music", "sfx"].iter() {
+            if let Some(entries) = audio_config.get(section).and_then(|v| v.as_array()) {
+                names.extend(entries.iter().filter_map(|e| json_str(e, "path")).map(String::from));
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+
+    let mut out = String::new();
+    writeln!(out, "pub static MANIFEST: &[u8] = include_bytes!({:?});", manifest_path).unwrap();
+    writeln!(out, "pub static FILES: &[(&str, &[u8])] = &[").unwrap();
+    for name in names.iter() {
+        let path = asset_root.join(name);
+        assert!(path.is_file(), "Can't embed missing asset {}", path.display());
+        println!("cargo:rerun-if-changed={}", path.display());
+        writeln!(out, "    ({:?}, include_bytes!({:?})),", name, path).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("embedded_assets.rs");
+    std::fs::write(&out_path, out).expect("Can't write embedded_assets.rs");
+}
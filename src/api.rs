@@ -1,21 +1,42 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use sdl2::event::Event;
-use sdl2::image::{InitFlag, LoadTexture};
+use sdl2::image::InitFlag;
 use sdl2::keyboard::Keycode;
 use sdl2::mixer::{AUDIO_S16LSB, DEFAULT_CHANNELS};
 use sdl2::pixels;
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
+use sdl2::render::BlendMode;
+use sdl2::surface::Surface;
 
 
+use log::{debug, info, warn};
 use serde::Deserialize;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
 use std::path::Path;
 
+use crate::assets::Assets;
+use crate::audio::{AudioBackend, AudioCall, AudioConfig, NullAudio, SfxParams, SynthBank};
+use crate::audio::{CHANNEL_ALL, CHANNEL_ANY, MUSIC_STOP, SFX_RELEASE, SFX_STOP};
+use crate::logging;
+use crate::logging::{AUDIO, GAME, GRAPHICS};
+use crate::manifest::Project;
+use crate::mixer::{AudioBus, AudioSettings, Falloff};
+use crate::p8;
+use crate::p8::{P8Cart, P8Music, P8Sfx};
+use crate::pathfind;
+use crate::pathfind::{FlowField, PathOptions, PathSearch};
+use crate::sdl_audio::SdlAudio;
+use crate::tiled::{TiledMap, TiledObject};
+use crate::autotile;
+use crate::autotile::Terrain;
+use crate::collision::{Aabb, CollisionConfig, Movement, TileCollider};
+use crate::tilemap;
+use crate::tilemap::{LayerScroll, MapAddressing, MapDraw, TileAnimations, TileFrame, TileMap};
+
 /*
 PICO 8 is 128 x 128 pixels
 each tile is 8x8
@@ -125,6 +146,25 @@ struct DrawState {
     camera_x: f32,
     camera_y: f32,
     pen: Color,
+    clip: Rect,
+}
+
+// Cached map layers are prerendered in chunks of this many cells square.
+const MAP_CHUNK_CELLS: u32 = 16;
+
+// A prerendered chunk of a cached map layer. Animated tiles are left out of the
+// texture and drawn over it each frame.
+struct MapChunk<'a> {
+    texture: sdl2::render::Texture<'a>,
+    // Cell positions within the chunk and the base sprite of animated tiles.
+    animated: Vec<(u32, u32, u8)>,
+}
+
+// An sfx played at a position in the world, by sfx_at.
+struct PositionalSfx {
+    name: String,
+    x: f32,
+    y: f32,
 }
 
 pub struct BBMicroApi<'a> {
@@ -132,12 +172,28 @@ pub struct BBMicroApi<'a> {
     texture_creator: &'a sdl2::render::TextureCreator<sdl2::video::WindowContext>,
     sprites_texture: sdl2::render::Texture<'a>,
     font_texture: sdl2::render::Texture<'a>,
-    sfx: HashMap<String,sdl2::mixer::Chunk>,
-    music: HashMap<String, sdl2::mixer::Music<'a>>,
+    audio: Box<dyn AudioBackend>,
+    synth: SynthBank,
+    audio_settings: AudioSettings,
+    audio_settings_path: Option<String>,
+    sound_falloff: Falloff,
+    // Positional sfx by the channel they play on, mixed again every frame.
+    positional_sfx: HashMap<i32, PositionalSfx>,
     font_entries: HashMap<char, FontEntry>,
+    palette: Vec<pixels::Color>,
     draw_state: DrawState,
     input_state: InputState,
-    map_data: [u8; 4 * 256 * 256],
+    map: TileMap,
+    map_addressing: MapAddressing,
+    map_empty_tile: Option<u8>,
+    map_cached_layers: HashSet<u8>,
+    map_layer_scroll: Vec<LayerScroll>,
+    map_chunks: HashMap<(u8, u32, u32), MapChunk<'a>>,
+    tile_animations: TileAnimations,
+    frame: u64,
+    sprite_flags: [u8; 256],
+    map_objects: Vec<TiledObject>,
+    project: Project,
 }
 
 #[derive(Deserialize, Debug)]
@@ -159,79 +215,302 @@ impl FontEntry {
     }
 }
 
-fn load_font() -> Result<HashMap<char, FontEntry>, Box<dyn Error>> {
-    let file = File::open("font.json")?;
-    let reader = BufReader::new(file);
+fn load_font(assets: &Assets, name: &str) -> Result<HashMap<char, FontEntry>, Box<dyn Error>> {
+    let bytes = assets.read(name)?;
 
     // Read the JSON contents of the file as an instance of `User`.
-    let font_entries: HashMap<char, FontEntry> = serde_json::from_reader(reader)?;
+    let font_entries: HashMap<char, FontEntry> = serde_json::from_slice(&bytes)?;
     // Return the `User`.
     Ok(font_entries)
 }
 
 
-#[derive(Deserialize, Debug)]
-struct AudioConfig {
-    music: Vec<AudioEntry>,
-    sfx: Vec<AudioEntry>
-}
+fn load_sprite_flags(assets: &Assets, name: &str) -> Result<[u8; 256], Box<dyn Error>> {
+    let bytes = assets.read(name)?;
 
-#[derive(Deserialize, Debug)]
-struct AudioEntry {
-    path: String,
-    name: String
+    let flags: Vec<u8> = serde_json::from_slice(&bytes)?;
+    if flags.len() > 256 {
+        return Err(format!("{} has {} sprite flags, at most 256 are allowed", name, flags.len()).into());
+    }
+
+    let mut sprite_flags = [0; 256];
+    sprite_flags[..flags.len()].copy_from_slice(&flags);
+    Ok(sprite_flags)
 }
 
-fn load_audio() -> Result<AudioConfig, Box<dyn Error>> {
-    let file = File::open("audio.json")?;
-    let reader = BufReader::new(file);
+pub fn load_audio(assets: &Assets, name: &str) -> Result<AudioConfig, Box<dyn Error>> {
+    let bytes = assets.read(name)?;
 
     // Read the JSON contents of the file as an instance of `AudioEntry`.
-    let audio_entries: AudioConfig = serde_json::from_reader(reader)?;
+    let audio_entries: AudioConfig = serde_json::from_slice(&bytes)
+        .map_err(|e| format!("Could not parse {}: {}", name, e))?;
+    audio_entries.validate().map_err(|e| format!("{}: {}", name, e))?;
     Ok(audio_entries)
 }
 
+// The synth sfx and patterns of a project: those in its audio config, or
+// its PICO-8 cart's when it has one.
+pub fn load_synth(project: &Project) -> Result<SynthBank, Box<dyn Error>> {
+    let config = load_audio(&project.assets, &project.manifest.audio)?;
+    let mut synth = SynthBank::from_config(&config)?;
+    if let Some(name) = &project.manifest.pico8 {
+        let bytes = project.assets.read(name)?;
+        synth.import(&P8Cart::from_bytes(name, &bytes)?);
+    }
+    Ok(synth)
+}
+
 impl<'a> BBMicroApi<'a> {
     pub fn new(
         canvas: &'a mut sdl2::render::WindowCanvas,
         texture_creator: &'a sdl2::render::TextureCreator<sdl2::video::WindowContext>,
+        project: Project,
     ) -> BBMicroApi<'a> {
-        let sprites_texture = texture_creator
-            .load_texture("sprites.png")
+        let manifest = &project.manifest;
+        let assets = &project.assets;
+
+        let sprites_texture = assets
+            .load_texture(texture_creator, &manifest.sprites)
             .expect("Couldn't load the texture");
-        let font_texture = texture_creator
-            .load_texture("font.png")
+        let font_texture = assets
+            .load_texture(texture_creator, &manifest.font.image)
             .expect("Couldn't load the texture");
 
-        let font_entries = load_font().expect("Could not load the font.json");
+        let font_entries = load_font(assets, &manifest.font.glyphs)
+            .expect("Could not load the font glyphs");
 
-        let audio_entries = load_audio().expect("Could not load the audio.json");
+        let sprite_flags = match &manifest.flags {
+            Some(flags) => load_sprite_flags(assets, flags).expect("Could not load the sprite flags"),
+            None => [0; 256],
+        };
 
-        let mut music = HashMap::new();
-        for musicName in audio_entries.music {
-            music.insert(musicName.name, sdl2::mixer::Music::from_file(musicName.path).expect("Missing music files"));
-        }
+        let audio_entries = load_audio(assets, &manifest.audio)
+            .expect("Could not load the audio config");
 
-        let mut sfx = HashMap::new();
-        for sfxName in audio_entries.sfx {
-            sfx.insert(sfxName.name, sdl2::mixer::Chunk::from_file(sfxName.path).expect("Missing wav files"));
-        }
+        let synth = SynthBank::from_config(&audio_entries).expect("Could not load the synth sfx");
+
+        // Without an audio device nothing plays, but the game runs the same.
+        let audio: Box<dyn AudioBackend> = if sdl2::mixer::query_spec().is_ok() {
+            Box::new(SdlAudio::new(assets, &audio_entries).expect("Could not load the audio"))
+        } else {
+            info!(target: AUDIO, "No audio device, sound is off");
+            Box::new(NullAudio::new(&audio_entries))
+        };
+
+        let audio_settings_path = manifest.sound.settings.clone();
+        let audio_settings = match &audio_settings_path {
+            Some(path) => AudioSettings::load(path).unwrap_or_else(|e| {
+                warn!(target: AUDIO, "Could not load the audio settings: {}", e);
+                AudioSettings::default()
+            }),
+            None => AudioSettings::default(),
+        };
 
-        BBMicroApi {
-            canvas: canvas,
-            texture_creator: texture_creator,
-            sprites_texture: sprites_texture,
-            font_texture: font_texture,
-            font_entries: font_entries,
+        let palette = manifest
+            .palette
+            .iter()
+            .map(|[r, g, b]| pixels::Color::RGB(*r, *g, *b))
+            .collect();
+
+        let mut api = BBMicroApi {
+            canvas,
+            texture_creator,
+            sprites_texture,
+            font_texture,
+            font_entries,
+            palette,
             draw_state: DrawState {
                 camera_x: 0.0,
                 camera_y: 0.0,
                 pen: 0,
+                clip: Rect::new(0, 0, 128, 128),
             },
             input_state: InputState::new(),
-            map_data: [0; 4 * 256 * 256],
-            sfx: sfx,
-            music: music,
+            map: TileMap::new(manifest.map.width, manifest.map.height, manifest.map.layers),
+            map_addressing: manifest.map.addressing,
+            map_empty_tile: manifest.map.empty_tile,
+            map_cached_layers: HashSet::new(),
+            map_layer_scroll: manifest.map.scroll.clone(),
+            map_chunks: HashMap::new(),
+            tile_animations: TileAnimations {
+                table: manifest.map.animations.clone(),
+            },
+            frame: 0,
+            sprite_flags,
+            audio,
+            synth,
+            audio_settings,
+            audio_settings_path,
+            sound_falloff: manifest.sound.falloff,
+            positional_sfx: HashMap::new(),
+            map_objects: Vec::new(),
+            project,
+        };
+        api.audio.apply_settings(api.audio_settings);
+
+        if let Some(name) = api.project.manifest.pico8.clone() {
+            let bytes = api.project.assets.read(&name).expect("Could not read the pico8 cart");
+            let cart = P8Cart::from_bytes(&name, &bytes).expect("Could not parse the pico8 cart");
+            api.import_p8(&cart).expect("Could not import the pico8 cart");
+        }
+
+        api
+    }
+
+    // Loads a map file from the project into the map layers, replacing what
+    // was there. Native .bbmap maps and Tiled .tmx and .tmj maps are supported.
+    pub fn load_map(&mut self, name: &str) -> Result<(), String> {
+        let assets = &self.project.assets;
+        if name.ends_with(tilemap::MAP_EXTENSION) {
+            let bytes = assets.read(name).map_err(|e| e.to_string())?;
+            let map = TileMap::read(&mut &bytes[..]).map_err(|e| format!("{}: {}", name, e))?;
+            return self.set_tile_map(map);
+        }
+
+        let map = TiledMap::load(name, &|file: &str| assets.read(file)).map_err(|e| e.to_string())?;
+        self.load_tiled_map(&map)
+    }
+
+    // Saves all map layers and their names to a .bbmap file.
+    pub fn map_save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        self.map.save(path).map_err(|e| e.to_string())
+    }
+
+    // Loads a .bbmap file from disk, replacing the map and its geometry.
+    pub fn map_load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let map = TileMap::load(path).map_err(|e| e.to_string())?;
+        self.set_tile_map(map)
+    }
+
+    fn set_tile_map(&mut self, map: TileMap) -> Result<(), String> {
+        TileMap::check_size(map.width, map.height, map.layer_count())?;
+
+        self.map = map;
+        self.map_objects.clear();
+        self.map_chunks.clear();
+        Ok(())
+    }
+
+    // Replaces the map with an empty one of the given size.
+    pub fn map_resize(&mut self, width: u32, height: u32, layers: u8) -> Result<(), String> {
+        TileMap::check_size(width, height, layers as usize)?;
+        self.map = TileMap::new(width, height, layers as usize);
+        self.map_objects.clear();
+        self.map_chunks.clear();
+        Ok(())
+    }
+
+    pub fn map_width(&self) -> u32 {
+        self.map.width
+    }
+
+    pub fn map_height(&self) -> u32 {
+        self.map.height
+    }
+
+    pub fn map_layers(&self) -> u8 {
+        self.map.layer_count() as u8
+    }
+
+    pub fn map_layer_name(&self, layer: u8) -> Option<&str> {
+        self.map.layer_names.get(layer as usize).map(|name| name.as_str())
+    }
+
+    pub fn set_map_layer_name(&mut self, layer: u8, name: &str) {
+        if let Some(layer_name) = self.map.layer_names.get_mut(layer as usize) {
+            *layer_name = name.to_string();
+        }
+    }
+
+    // Replaces the map with a Tiled map. The map takes the Tiled map's size
+    // and one layer per tile layer, in order, but keeps at least as many
+    // layers as the manifest asks for. The map objects are replaced with the
+    // map's object layers.
+    pub fn load_tiled_map(&mut self, map: &TiledMap) -> Result<(), String> {
+        if map.layers.len() > u8::MAX as usize {
+            return Err(format!("The map has {} tile layers, at most 255 are supported", map.layers.len()));
+        }
+
+        let width = map.layers.iter().map(|l| l.width).fold(map.width, u32::max);
+        let height = map.layers.iter().map(|l| l.height).fold(map.height, u32::max);
+        let layers = map.layers.len().max(self.project.manifest.map.layers).max(1);
+
+        let mut tile_map = TileMap::new(width, height, layers);
+        for (layer, tiled_layer) in map.layers.iter().enumerate() {
+            for (i, gid) in tiled_layer.gids.iter().enumerate() {
+                let x = i as u32 % tiled_layer.width;
+                let y = i as u32 / tiled_layer.width;
+                tile_map.set(x, y, layer, map.sprite(*gid).map_err(|e| e.to_string())?);
+            }
+            tile_map.layer_names[layer] = tiled_layer.name.clone();
+        }
+
+        for (base, frames) in map.tile_animations().map_err(|e| e.to_string())? {
+            self.tile_animations.set(base, frames);
+        }
+
+        self.map = tile_map;
+        self.map_objects = map.objects.clone();
+        self.map_chunks.clear();
+        Ok(())
+    }
+
+    // The objects from the object layers of the last loaded Tiled map.
+    pub fn map_objects(&self) -> &[TiledObject] {
+        &self.map_objects
+    }
+
+    pub fn map_object(&self, name: &str) -> Option<&TiledObject> {
+        self.map_objects.iter().find(|object| object.name == name)
+    }
+
+    pub fn map_objects_of_kind<'b>(&'b self, kind: &'b str) -> impl Iterator<Item = &'b TiledObject> {
+        self.map_objects.iter().filter(move |object| object.kind == kind)
+    }
+
+    // Replaces the spritesheet, sprite flags and map layer 0 with the contents
+    // of a PICO-8 cart. Sprite color 0 is transparent, as in PICO-8.
+    pub fn import_p8(&mut self, cart: &P8Cart) -> Result<(), String> {
+        let mut pixels = vec![0; 128 * 128 * 4];
+        for y in 0..128 {
+            for x in 0..128 {
+                let col = cart.gfx_pixel(x, y);
+                let color = self.to_sdl_color(col);
+                let i = ((y * 128 + x) * 4) as usize;
+                pixels[i] = color.r;
+                pixels[i + 1] = color.g;
+                pixels[i + 2] = color.b;
+                pixels[i + 3] = if col == 0 { 0 } else { 255 };
+            }
+        }
+        let surface = Surface::from_data(&mut pixels, 128, 128, 128 * 4, PixelFormatEnum::RGBA32)?;
+        let mut texture = self
+            .texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string())?;
+        texture.set_blend_mode(BlendMode::Blend);
+        self.sprites_texture = texture;
+
+        self.sprite_flags.copy_from_slice(cart.sprite_flags());
+
+        for y in 0..p8::MAP_HEIGHT {
+            for x in 0..p8::MAP_WIDTH {
+                self.map.set(x, y, 0, cart.map_cell(x, y));
+            }
+        }
+        self.map_chunks.clear();
+
+        self.synth.import(cart);
+        self.audio.synth_changed();
+
+        Ok(())
+    }
+
+    // Debug output for the game, as in PICO-8: a line to stderr, or appended
+    // to a file.
+    pub fn printh(&self, text: &str, file: Option<&str>) {
+        if let Err(err) = logging::printh(text, file.map(Path::new)) {
+            warn!(target: GAME, "Could not printh to {}: {}", file.unwrap_or("stderr"), err);
         }
     }
 
@@ -241,24 +520,9 @@ impl<'a> BBMicroApi<'a> {
     }
 
     fn to_sdl_color(&self, col: Color) -> pixels::Color {
-        match col {
-            0 => pixels::Color::RGB(0, 0, 0),
-            1 => pixels::Color::RGB(29, 43, 83),
-            2 => pixels::Color::RGB(126, 37, 83),
-            3 => pixels::Color::RGB(0, 135, 81),
-            4 => pixels::Color::RGB(117, 82, 54),
-            5 => pixels::Color::RGB(95, 87, 79),
-            6 => pixels::Color::RGB(194, 195, 199),
-            7 => pixels::Color::RGB(255, 241, 232),
-            8 => pixels::Color::RGB(255, 0, 77),
-            9 => pixels::Color::RGB(255, 163, 0),
-            10 => pixels::Color::RGB(255, 236, 39),
-            11 => pixels::Color::RGB(0, 228, 54),
-            12 => pixels::Color::RGB(41, 173, 255),
-            13 => pixels::Color::RGB(131, 118, 156),
-            14 => pixels::Color::RGB(255, 119, 168),
-            15 => pixels::Color::RGB(0255, 204, 170),
-            _ => pixels::Color::RGB(0, 0, 0),
+        match self.palette.get(col as usize) {
+            Some(color) => *color,
+            None => pixels::Color::RGB(0, 0, 0),
         }
     }
 
@@ -276,7 +540,16 @@ impl<'a> BBMicroApi<'a> {
 
     pub fn circfill(&mut self, x: f32, y: f32, r: f32, col: Color) {}
 
-    pub fn clip(&mut self, x: f32, y: f32, w: f32, h: f32) {}
+    pub fn clip(&mut self, x: f32, y: f32, w: f32, h: f32) {
+        let rect = Rect::new(x as i32, y as i32, w.max(0.0) as u32, h.max(0.0) as u32);
+        self.draw_state.clip = rect;
+        self.canvas.set_clip_rect(rect);
+    }
+
+    pub fn clip_reset(&mut self) {
+        self.draw_state.clip = Rect::new(0, 0, 128, 128);
+        self.canvas.set_clip_rect(None);
+    }
 
     pub fn cls(&mut self, col: Color) {
         self.canvas.set_draw_color(self.to_sdl_color(col));
@@ -287,13 +560,32 @@ impl<'a> BBMicroApi<'a> {
         self.draw_state.pen = col;
     }
 
+    pub fn fget(&self, n: u8, f: u8) -> bool {
+        assert_eq!(f < 8, true);
+        self.sprite_flags[n as usize] & (1 << f) != 0
+    }
+
+    pub fn fget_all(&self, n: u8) -> u8 {
+        self.sprite_flags[n as usize]
+    }
+
+    pub fn fset(&mut self, n: u8, f: u8, v: bool) {
+        assert_eq!(f < 8, true);
+        if v {
+            self.sprite_flags[n as usize] |= 1 << f;
+        } else {
+            self.sprite_flags[n as usize] &= !(1 << f);
+        }
+    }
+
+    pub fn fset_all(&mut self, n: u8, flags: u8) {
+        self.sprite_flags[n as usize] = flags;
+    }
+
     pub fn spr(&mut self, n: u8, x: f32, y: f32, w: f32, h: f32, flip_x: bool, flip_y: bool) {
         let (x, y) = self.to_camera(x, y);
 
-        let src_x = (n % 16) * 8;
-        let src_y = (n / 16) * 8;
-
-        let src_rect = Rect::new(src_x as i32, src_y as i32, 8, 8);
+        let src_rect = sprite_rect(n);
         let dst_rect = Rect::new(x as i32, y as i32, 8, 8);
         self.canvas
             .copy(&self.sprites_texture, src_rect, dst_rect)
@@ -349,6 +641,9 @@ impl<'a> BBMicroApi<'a> {
 
     pub fn flip(&mut self) {
         self.canvas.present();
+        self.frame += 1;
+        self.audio.update(&self.synth);
+        self.update_positional_sfx();
 
         ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 30));
     }
@@ -379,85 +674,590 @@ impl<'a> BBMicroApi<'a> {
         }
     }
 
-    pub fn mset(&mut self, celx: u32, cely: u32, layer: u8, snum: u8) {
-        assert_eq!(layer < 4, true);
-        let offset = layer as usize * 256 * 256;
+    // Sets how mget, mset, map and the other map calls treat cells outside
+    // the map: bounded (the default), wrap or clamp.
+    pub fn set_map_addressing(&mut self, addressing: MapAddressing) {
+        self.map_addressing = addressing;
+    }
 
-        assert_eq!(celx < 256, true);
-        assert_eq!(cely < 256, true);
-        self.map_data[celx as usize + (cely as usize) * 256 + offset] = snum;
+    pub fn mset(&mut self, celx: i32, cely: i32, layer: u8, snum: u8) {
+        if let Some((x, y)) = self.map.resolve(celx, cely, self.map_addressing) {
+            if self.map.set(x, y, layer as usize, snum) {
+                self.invalidate_map_cell(layer, x, y);
+            }
+        }
     }
 
-    pub fn mget(&mut self, celx: u32, cely: u32, layer: u8, snum: u8) -> u8 {
-        assert_eq!(layer < 4, true);
-        let offset = layer as usize * 256 * 256;
+    // Drops the cached chunk a cell was drawn into.
+    fn invalidate_map_cell(&mut self, layer: u8, x: u32, y: u32) {
+        self.map_chunks
+            .remove(&(layer, x / MAP_CHUNK_CELLS, y / MAP_CHUNK_CELLS));
+    }
 
-        assert_eq!(celx < 256, true);
-        assert_eq!(cely < 256, true);
-        return self.map_data[celx as usize + (cely as usize) * 256 + offset];
+    // Adds an autotiling rule set, replacing any terrain with the same name.
+    pub fn add_terrain(&mut self, terrain: Terrain) -> Result<(), String> {
+        terrain.validate()?;
+        let terrains = &mut self.project.manifest.map.terrains;
+        terrains.retain(|t| t.name != terrain.name);
+        terrains.push(terrain);
+        Ok(())
     }
 
-    pub fn map(&mut self, celx: u32, cely: u32, sx: f32, sy: f32, celw: u32, celh: u32, layer: u8) {
-        assert_eq!(layer < 4, true);
-        let offset = layer as usize * 256 * 256;
+    fn terrain(&self, name: &str) -> Result<Terrain, String> {
+        self.project
+            .manifest
+            .map
+            .terrains
+            .iter()
+            .find(|t| t.name == name)
+            .cloned()
+            .ok_or_else(|| format!("No terrain named {}", name))
+    }
 
-        for i_x in 0..celw {
-            for i_y in 0..celh {
-                let t_x = (i_x + celx) as usize;
-                let t_y = (i_y + cely) as usize;
-                if !(t_x <= 255 && t_y <= 255) {
-                    continue;
-                }
+    // Paints a cell with a terrain and picks the sprites of it and its
+    // neighbors from the terrain's rules.
+    pub fn mset_terrain(&mut self, celx: i32, cely: i32, layer: u8, terrain: &str) -> Result<(), String> {
+        let terrain = self.terrain(terrain)?;
+        let changed = autotile::place(&mut self.map, &terrain, celx, cely, layer as usize, self.map_addressing);
+        for (x, y) in changed {
+            self.invalidate_map_cell(layer, x, y);
+        }
+        Ok(())
+    }
+
+    // Sets a cell to a plain sprite and fixes up the terrain around it, to
+    // cut holes in a terrain.
+    pub fn mclear_terrain(&mut self, celx: i32, cely: i32, layer: u8, terrain: &str, snum: u8) -> Result<(), String> {
+        let terrain = self.terrain(terrain)?;
+        let changed = autotile::erase(&mut self.map, &terrain, celx, cely, layer as usize, snum, self.map_addressing);
+        for (x, y) in changed {
+            self.invalidate_map_cell(layer, x, y);
+        }
+        Ok(())
+    }
+
+    // Resolves every cell of a terrain on a layer, after loading a map that
+    // was painted with any of the terrain's sprites.
+    pub fn map_autotile(&mut self, layer: u8, terrain: &str) -> Result<(), String> {
+        let terrain = self.terrain(terrain)?;
+        if autotile::retile_layer(&mut self.map, &terrain, layer as usize, self.map_addressing) > 0 {
+            self.map_chunks.retain(|(chunk_layer, _, _), _| *chunk_layer != layer);
+        }
+        Ok(())
+    }
+
+    // Cells outside the map read as 0.
+    pub fn mget(&self, celx: i32, cely: i32, layer: u8) -> u8 {
+        self.mget_checked(celx, cely, layer).unwrap_or(0)
+    }
+
+    // Like mget, but None outside the map.
+    pub fn mget_checked(&self, celx: i32, cely: i32, layer: u8) -> Option<u8> {
+        self.map.sample(celx, cely, layer as usize, self.map_addressing)
+    }
+
+    // Sets the layer collision checks use and which sprite flags make a tile
+    // solid, one way or a slope.
+    pub fn set_collision(&mut self, config: CollisionConfig) -> Result<(), String> {
+        config.validate()?;
+        self.project.manifest.map.collision = config;
+        Ok(())
+    }
+
+    fn collider(&self) -> TileCollider<'_> {
+        TileCollider {
+            map: &self.map,
+            flags: &self.sprite_flags,
+            config: self.project.manifest.map.collision,
+            addressing: self.map_addressing,
+        }
+    }
+
+    // Whether the pixel at (x, y) is in a solid tile or under a slope.
+    pub fn solid_at(&self, x: f32, y: f32) -> bool {
+        self.collider().solid_at(x, y)
+    }
+
+    // Whether the box overlaps a tile whose sprite has flag f.
+    pub fn rect_overlaps_flag(&self, rect: Aabb, f: u8) -> bool {
+        self.collider().rect_overlaps_flag(rect, f)
+    }
 
-                let tile = self.map_data[t_x + t_y * 256 + offset];
-
-                // Get the sprite value at the current location
-                self.spr(
-                    tile,
-                    sx + (i_x as f32 * 8.0),
-                    sy + (i_y as f32 * 8.0),
-                    8 as f32,
-                    8 as f32,
-                    false,
-                    false,
+    // Moves a box by (dx, dy) against the collision layer, sliding along
+    // walls and floors. The result has the new position and every tile hit
+    // with its contact normal.
+    pub fn move_and_slide(&self, rect: Aabb, dx: f32, dy: f32) -> Movement {
+        self.collider().move_and_slide(rect, dx, dy)
+    }
+
+    // A pathfinding cost function where cells on a layer whose sprite has
+    // flag f are blocked and the rest cost 1.
+    pub fn path_cost(&self, layer: u8, f: u8) -> impl Fn(i32, i32) -> Option<f32> + '_ {
+        pathfind::flag_cost(&self.map, &self.sprite_flags, layer as usize, f)
+    }
+
+    // Finds a path between two cells, avoiding cells on a layer whose sprite
+    // has flag f. The path includes both ends, path_to_pixels turns it into
+    // the middles of the cells. Fails if the options' step costs aren't
+    // above 0.
+    pub fn map_path(&self, start: (i32, i32), goal: (i32, i32), layer: u8, f: u8, options: PathOptions) -> Result<Option<Vec<(i32, i32)>>, String> {
+        self.map_path_with(start, goal, options, &self.path_cost(layer, f))
+    }
+
+    // Like map_path, with walkability and costs from a callback.
+    pub fn map_path_with(&self, start: (i32, i32), goal: (i32, i32), options: PathOptions, cost: &dyn Fn(i32, i32) -> Option<f32>) -> Result<Option<Vec<(i32, i32)>>, String> {
+        options.validate()?;
+        Ok(pathfind::find_path(self.map.width, self.map.height, start, goal, options, cost))
+    }
+
+    // A search over the map to run a budget at a time with PathSearch::step,
+    // for spreading long searches over several frames.
+    pub fn map_path_search(&self, start: (i32, i32), goal: (i32, i32), options: PathOptions) -> Result<PathSearch, String> {
+        options.validate()?;
+        Ok(PathSearch::new(self.map.width, self.map.height, start, goal, options))
+    }
+
+    // A flow field over the map towards the goals, filled in with
+    // FlowField::step.
+    pub fn map_flow_field(&self, goals: &[(i32, i32)], options: PathOptions) -> Result<FlowField, String> {
+        options.validate()?;
+        Ok(FlowField::new(self.map.width, self.map.height, goals, options))
+    }
+
+    // Makes cells holding `base` cycle through `frames` when drawn with map,
+    // an empty list stops animating it. Frames advance with flip, 30 a second.
+    pub fn set_tile_animation(&mut self, base: u8, frames: Vec<TileFrame>) {
+        self.tile_animations.set(base, frames);
+        self.map_chunks.clear();
+    }
+
+    // The number of frames flipped so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    fn animation_time_ms(&self) -> u64 {
+        self.frame * 1000 / 30
+    }
+
+    // Sets which sprite map leaves undrawn, None to draw every cell. The
+    // default is None, Some(0) skips sprite 0 as in PICO-8.
+    pub fn set_map_empty_tile(&mut self, tile: Option<u8>) {
+        if self.map_empty_tile != tile {
+            self.map_empty_tile = tile;
+            self.map_chunks.clear();
+        }
+    }
+
+    // Marks a layer as static. Its cells are prerendered into chunk textures
+    // that map copies whole, and a chunk is rendered again after an mset in
+    // it. This only applies with bounded addressing.
+    pub fn map_cache_layer(&mut self, layer: u8, cached: bool) {
+        if cached {
+            self.map_cached_layers.insert(layer);
+        } else {
+            self.map_cached_layers.remove(&layer);
+            self.map_chunks.retain(|(chunk_layer, _, _), _| *chunk_layer != layer);
+        }
+    }
+
+    // Sets the parallax factors, offset and wrapping map uses for a layer.
+    pub fn set_map_layer_scroll(&mut self, layer: u8, scroll: LayerScroll) {
+        let layer = layer as usize;
+        if self.map_layer_scroll.len() <= layer {
+            self.map_layer_scroll.resize(layer + 1, LayerScroll::default());
+        }
+        self.map_layer_scroll[layer] = scroll;
+    }
+
+    pub fn map_layer_scroll(&self, layer: u8) -> LayerScroll {
+        self.map_layer_scroll
+            .get(layer as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Draws the cells of the region that land inside the clip window, moved
+    // by the layer's scroll settings or the ones the MapDraw gives.
+    pub fn map(&mut self, draw: MapDraw) {
+        let scroll = draw.scroll.unwrap_or_else(|| self.map_layer_scroll(draw.layer));
+        let (ox, oy) = scroll.origin(draw.sx, draw.sy, self.draw_state.camera_x, self.draw_state.camera_y);
+        let clip = self.draw_state.clip;
+        let xs = tilemap::wrap_origins(ox, draw.celw as f32 * 8.0, scroll.wrap_x, clip.x() as f32, clip.width() as f32);
+        let ys = tilemap::wrap_origins(oy, draw.celh as f32 * 8.0, scroll.wrap_y, clip.y() as f32, clip.height() as f32);
+        for oy in &ys {
+            for ox in &xs {
+                self.map_region(MapDraw { sx: *ox, sy: *oy, ..draw });
+            }
+        }
+    }
+
+    // Draws a region of the map with its top left cell at screen position
+    // (sx, sy), already scrolled, skipping cells outside the clip window.
+    fn map_region(&mut self, draw: MapDraw) {
+        let clip = self.draw_state.clip;
+        let xs = tilemap::visible_cells(draw.sx, clip.x() as f32, clip.width() as f32, draw.celw);
+        let ys = tilemap::visible_cells(draw.sy, clip.y() as f32, clip.height() as f32, draw.celh);
+        if xs.is_empty() || ys.is_empty() {
+            return;
+        }
+
+        if self.map_cached_layers.contains(&draw.layer) && self.map_addressing == MapAddressing::Bounded {
+            self.map_from_chunks(MapDraw {
+                celx: draw.celx + xs.start as i32,
+                cely: draw.cely + ys.start as i32,
+                sx: draw.sx + xs.start as f32 * 8.0,
+                sy: draw.sy + ys.start as f32 * 8.0,
+                celw: xs.len() as u32,
+                celh: ys.len() as u32,
+                ..draw
+            });
+            return;
+        }
+
+        let MapDraw { celx, cely, sx: ox, sy: oy, layer, .. } = draw;
+        let time_ms = self.animation_time_ms();
+        for i_x in xs {
+            for i_y in ys.clone() {
+                let tile = match self.mget_checked(celx + i_x as i32, cely + i_y as i32, layer) {
+                    Some(tile) if Some(tile) != self.map_empty_tile => tile,
+                    _ => continue,
+                };
+                let tile = self.tile_animations.resolve(tile, time_ms);
+
+                let dst_rect = Rect::new(
+                    (ox + i_x as f32 * 8.0) as i32,
+                    (oy + i_y as f32 * 8.0) as i32,
+                    8,
+                    8,
                 );
+                self.canvas
+                    .copy(&self.sprites_texture, sprite_rect(tile), dst_rect)
+                    .unwrap();
             }
         }
     }
 
-    pub fn sfx(&mut self, audio: &str, channel: i32, offset: u32, length: u32) {
-        //TODO offset and length unused
-        match self.sfx.get(audio) {
-            Some(audio) => {
-                match sdl2::mixer::Channel(channel).play(&audio, 0) {
-                    Ok(_) => {
-                        print!("Success!\n");
-                    },
-                    Err(err) => {
-                        print!("{}", &err);
+    // Copies the region's cells of a cached layer from its chunk textures,
+    // with the top left cell at screen position (sx, sy).
+    fn map_from_chunks(&mut self, draw: MapDraw) {
+        let MapDraw { celx, cely, sx: dx, sy: dy, celw, celh, layer, .. } = draw;
+        let x0 = celx.max(0);
+        let y0 = cely.max(0);
+        let x1 = (celx + celw as i32).min(self.map.width as i32);
+        let y1 = (cely + celh as i32).min(self.map.height as i32);
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+        let (x0, y0, x1, y1) = (x0 as u32, y0 as u32, x1 as u32, y1 as u32);
+        let time_ms = self.animation_time_ms();
+
+        for chunk_y in y0 / MAP_CHUNK_CELLS..=(y1 - 1) / MAP_CHUNK_CELLS {
+            for chunk_x in x0 / MAP_CHUNK_CELLS..=(x1 - 1) / MAP_CHUNK_CELLS {
+                if let Err(err) = self.render_map_chunk(layer, chunk_x, chunk_y) {
+                    warn!(target: GRAPHICS, "Could not render map layer {} chunk {}, {}: {}", layer, chunk_x, chunk_y, err);
+                    return;
+                }
+
+                let cx0 = x0.max(chunk_x * MAP_CHUNK_CELLS);
+                let cy0 = y0.max(chunk_y * MAP_CHUNK_CELLS);
+                let cx1 = x1.min((chunk_x + 1) * MAP_CHUNK_CELLS);
+                let cy1 = y1.min((chunk_y + 1) * MAP_CHUNK_CELLS);
+                let src_rect = Rect::new(
+                    ((cx0 - chunk_x * MAP_CHUNK_CELLS) * 8) as i32,
+                    ((cy0 - chunk_y * MAP_CHUNK_CELLS) * 8) as i32,
+                    (cx1 - cx0) * 8,
+                    (cy1 - cy0) * 8,
+                );
+                let dst_rect = Rect::new(
+                    (dx + (cx0 as i32 - celx) as f32 * 8.0) as i32,
+                    (dy + (cy0 as i32 - cely) as f32 * 8.0) as i32,
+                    (cx1 - cx0) * 8,
+                    (cy1 - cy0) * 8,
+                );
+                let chunk = &self.map_chunks[&(layer, chunk_x, chunk_y)];
+                self.canvas.copy(&chunk.texture, src_rect, dst_rect).unwrap();
+
+                for (x, y, base) in &chunk.animated {
+                    let cell_x = chunk_x * MAP_CHUNK_CELLS + x;
+                    let cell_y = chunk_y * MAP_CHUNK_CELLS + y;
+                    if cell_x < cx0 || cell_x >= cx1 || cell_y < cy0 || cell_y >= cy1 {
+                        continue;
                     }
+                    let tile = self.tile_animations.resolve(*base, time_ms);
+                    let dst_rect = Rect::new(
+                        (dx + (cell_x as i32 - celx) as f32 * 8.0) as i32,
+                        (dy + (cell_y as i32 - cely) as f32 * 8.0) as i32,
+                        8,
+                        8,
+                    );
+                    self.canvas
+                        .copy(&self.sprites_texture, sprite_rect(tile), dst_rect)
+                        .unwrap();
                 }
             }
-            None => print!("no sfx found")
         }
     }
 
-    pub fn music(&mut self, audio: &str, fadems: u32, channelmask: u32) {
-        //TODO fadems and channelmask unused
-        match &self.music.get(audio) {
-            Some(audio) => {
-                match audio.play(-1) {
-                    Ok(_) => {
-                        print!("Success!\n");
-                    },
-                    Err(err) => {
-                        print!("{}", &err);
+    // Prerenders a chunk of a cached layer if it isn't already.
+    fn render_map_chunk(&mut self, layer: u8, chunk_x: u32, chunk_y: u32) -> Result<(), String> {
+        if self.map_chunks.contains_key(&(layer, chunk_x, chunk_y)) {
+            return Ok(());
+        }
+
+        let size = MAP_CHUNK_CELLS * 8;
+        let mut texture = self
+            .texture_creator
+            .create_texture_target(PixelFormatEnum::RGBA8888, size, size)
+            .map_err(|e| e.to_string())?;
+        texture.set_blend_mode(BlendMode::Blend);
+
+        let map = &self.map;
+        let sprites_texture = &self.sprites_texture;
+        let empty_tile = self.map_empty_tile;
+        let animations = &self.tile_animations;
+        let mut animated = Vec::new();
+        self.canvas
+            .with_texture_canvas(&mut texture, |canvas| {
+                canvas.set_draw_color(pixels::Color::RGBA(0, 0, 0, 0));
+                canvas.clear();
+                for y in 0..MAP_CHUNK_CELLS {
+                    for x in 0..MAP_CHUNK_CELLS {
+                        let cell_x = chunk_x * MAP_CHUNK_CELLS + x;
+                        let cell_y = chunk_y * MAP_CHUNK_CELLS + y;
+                        match map.get(cell_x, cell_y, layer as usize) {
+                            Some(tile) if Some(tile) == empty_tile => {}
+                            Some(tile) if animations.is_animated(tile) => animated.push((x, y, tile)),
+                            Some(tile) => {
+                                let dst_rect = Rect::new((x * 8) as i32, (y * 8) as i32, 8, 8);
+                                canvas.copy(sprites_texture, sprite_rect(tile), dst_rect).unwrap();
+                            }
+                            _ => {}
+                        }
                     }
                 }
+            })
+            .map_err(|e| e.to_string())?;
+
+        self.map_chunks
+            .insert((layer, chunk_x, chunk_y), MapChunk { texture, animated });
+        Ok(())
+    }
+
+    // Replaces synth sfx n, which can then be played with sfx("n").
+    pub fn define_sfx(&mut self, n: usize, sfx: P8Sfx) -> Result<(), String> {
+        if n >= p8::SFX_COUNT {
+            return Err(format!("sfx {} is out of range, there are {}", n, p8::SFX_COUNT));
+        }
+        self.synth.sfx[n] = sfx;
+        // Sfx 0..7 can be instruments of the others.
+        self.audio.synth_changed();
+        Ok(())
+    }
+
+    pub fn sfx_data(&self, n: usize) -> Option<&P8Sfx> {
+        self.synth.sfx.get(n)
+    }
+
+    // Plays a sound from the audio config by name. Synth sfx can also be
+    // played by number, e.g. sfx("3") for sfx 3 of an imported cart.
+    //
+    // For synth sfx `offset` is the first note and `length` how many notes
+    // to play; for recorded ones they're in milliseconds. A length of 0 plays
+    // to the end. See SFX_STOP, SFX_RELEASE, CHANNEL_ANY and CHANNEL_ALL for
+    // the special values.
+    pub fn sfx(&mut self, audio: &str, channel: i32, offset: u32, length: u32) {
+        self.sfx_with(audio, channel, offset, length, 1.0, 0.0);
+    }
+
+    // sfx() at a volume (0.0..1.0, on top of the sfx bus and the sound's own
+    // volume in the audio config) and a stereo pan from -1.0 (left) to 1.0
+    // (right).
+    pub fn sfx_with(&mut self, audio: &str, channel: i32, offset: u32, length: u32, volume: f32, pan: f32) {
+        let params = SfxParams {
+            channel,
+            offset,
+            length,
+            volume,
+            pan,
+        };
+        if let Some(channel) = self.play_sfx(audio, params) {
+            self.positional_sfx.remove(&channel);
+        }
+    }
+
+    // Plays an sfx at a position in the world on any free channel. It's
+    // quieter the further it is from the middle of the screen and pans with
+    // how far left or right of it it is, following the camera as it moves.
+    pub fn sfx_at(&mut self, audio: &str, x: f32, y: f32) {
+        let (volume, pan) = self.positional_mix(x, y);
+        let params = SfxParams {
+            channel: CHANNEL_ANY,
+            offset: 0,
+            length: 0,
+            volume,
+            pan,
+        };
+        if let Some(channel) = self.play_sfx(audio, params) {
+            let name = audio.to_string();
+            self.positional_sfx.insert(channel, PositionalSfx { name, x, y });
+        }
+    }
+
+    // Plays an sfx as its entry in the audio config says: at its volume and
+    // not again until its cooldown is over. Returns the channel it plays on.
+    fn play_sfx(&mut self, audio: &str, mut params: SfxParams) -> Option<i32> {
+        let special = audio == SFX_STOP || audio == SFX_RELEASE || params.channel == CHANNEL_ALL;
+        if special {
+            return self.audio.sfx(&self.synth, audio, params);
+        }
+        let now = Instant::now();
+        if !self.audio.sounds().cooldown_ready(audio, now) {
+            debug!(target: AUDIO, "sfx {} is cooling down", audio);
+            return None;
+        }
+        params.volume *= self.audio.sounds().sfx_volume(audio);
+        let channel = self.audio.sfx(&self.synth, audio, params);
+        if channel.is_some() {
+            self.audio.sounds_mut().played(audio, now);
+        }
+        channel
+    }
+
+    // Stops every sfx and music in a group of the audio config.
+    pub fn stop_group(&mut self, group: &str) {
+        // There are at most 32 channels, see sound.channels.
+        for channel in 0..32 {
+            let playing = self.audio.sfx_playing(channel).map(str::to_string);
+            if let Some(name) = playing {
+                if self.audio.sounds().sfx_in_group(&name, group) {
+                    self.sfx(SFX_STOP, channel, 0, 0);
+                }
+            }
+        }
+        let music = self.audio.music_playing().map(str::to_string);
+        if let Some(name) = music {
+            if self.audio.sounds().music_in_group(&name, group) {
+                self.audio.music(&self.synth, MUSIC_STOP, 0, 0);
             }
-            None => print!("no music found")
         }
     }
+
+    // How sfx_at sounds fade and pan with distance.
+    pub fn set_sound_falloff(&mut self, falloff: Falloff) -> Result<(), String> {
+        falloff.validate()?;
+        self.sound_falloff = falloff;
+        Ok(())
+    }
+
+    pub fn sound_falloff(&self) -> Falloff {
+        self.sound_falloff
+    }
+
+    // The volume and pan of a sound at x, y heard from the middle of the
+    // screen.
+    fn positional_mix(&self, x: f32, y: f32) -> (f32, f32) {
+        let (dx, dy) = self.to_camera(x, y);
+        self.sound_falloff.attenuate(dx - 64.0, dy - 64.0)
+    }
+
+    // Mixes positional sfx again for where the camera is now, and forgets
+    // those that ended.
+    fn update_positional_sfx(&mut self) {
+        let audio = &self.audio;
+        self.positional_sfx
+            .retain(|channel, playing| audio.sfx_playing(*channel) == Some(playing.name.as_str()));
+        let channels: Vec<i32> = self.positional_sfx.keys().copied().collect();
+        for channel in channels {
+            let playing = &self.positional_sfx[&channel];
+            let (volume, pan) = self.positional_mix(playing.x, playing.y);
+            let volume = volume * self.audio.sounds().sfx_volume(&playing.name);
+            self.audio.set_sfx_mix(channel, volume, pan);
+        }
+    }
+
+    // The name of the sfx playing on a channel, like stat(16..19) in PICO-8.
+    pub fn sfx_playing(&self, channel: i32) -> Option<&str> {
+        self.audio.sfx_playing(channel)
+    }
+
+    // The note a synth sfx is playing on a channel, like stat(20..23).
+    pub fn sfx_note(&self, channel: i32) -> Option<usize> {
+        self.audio.sfx_note(&self.synth, channel)
+    }
+
+    // Replaces music pattern n, which can then be played with music("n").
+    pub fn define_music(&mut self, n: usize, pattern: P8Music) -> Result<(), String> {
+        if n >= p8::MUSIC_COUNT {
+            return Err(format!("music pattern {} is out of range, there are {}", n, p8::MUSIC_COUNT));
+        }
+        self.synth.patterns[n] = pattern;
+        self.audio.synth_changed();
+        Ok(())
+    }
+
+    pub fn music_data(&self, n: usize) -> Option<&P8Music> {
+        self.synth.patterns.get(n)
+    }
+
+    // Plays music from the audio config by name, or the song starting at a
+    // music pattern by its name or number, e.g. music("0", 500, 0). Music
+    // that was playing fades out over `fadems` as the new music fades in,
+    // and MUSIC_STOP fades it out. The mixer channels in `channelmask` are
+    // kept for the music; sfx on CHANNEL_ANY never take them.
+    pub fn music(&mut self, audio: &str, fadems: u32, channelmask: u32) {
+        self.audio.music(&self.synth, audio, fadems, channelmask);
+    }
+
+    // The name of the music playing.
+    pub fn music_playing(&self) -> Option<&str> {
+        self.audio.music_playing()
+    }
+
+    // The sfx and music calls so far when sound is off, for tests.
+    pub fn audio_calls(&self) -> &[AudioCall] {
+        self.audio.calls()
+    }
+
+    // Applies and saves changed audio settings.
+    fn audio_settings_changed(&mut self) {
+        self.audio.apply_settings(self.audio_settings);
+        if let Some(path) = &self.audio_settings_path {
+            if let Err(err) = self.audio_settings.save(path) {
+                warn!(target: AUDIO, "Could not save the audio settings: {}", err);
+            }
+        }
+    }
+
+    // The volume of a bus, 0.0..1.0.
+    pub fn volume(&self, bus: AudioBus) -> f32 {
+        self.audio_settings.volume(bus)
+    }
+
+    pub fn set_volume(&mut self, bus: AudioBus, volume: f32) {
+        self.audio_settings.set_volume(bus, volume);
+        self.audio_settings_changed();
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.audio_settings.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.audio_settings.muted = muted;
+        self.audio_settings_changed();
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.set_muted(!self.audio_settings.muted);
+    }
+}
+
+// Where sprite n sits on the spritesheet.
+fn sprite_rect(n: u8) -> Rect {
+    let src_x = (n % 16) * 8;
+    let src_y = (n / 16) * 8;
+    Rect::new(src_x as i32, src_y as i32, 8, 8)
 }
 
 pub trait BBMicroGame {
@@ -0,0 +1,101 @@
+use sdl2::image::LoadTexture;
+use sdl2::mixer::{Chunk, LoaderRWops, Music};
+use sdl2::render::{Texture, TextureCreator};
+use sdl2::rwops::RWops;
+use sdl2::video::WindowContext;
+
+use log::debug;
+
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::cart::Cart;
+use crate::logging::ASSETS;
+
+// Assets baked into the executable by build.rs. Only release builds with the
+// embed-assets feature use them, debug builds keep reading from disk.
+#[cfg(all(feature = "embed-assets", not(debug_assertions)))]
+pub mod embedded {
+    include!(concat!(env!("OUT_DIR"), "/embedded_assets.rs"));
+
+    pub fn get(name: &str) -> Option<&'static [u8]> {
+        FILES
+            .iter()
+            .find(|(file_name, _)| *file_name == name)
+            .map(|(_, bytes)| *bytes)
+    }
+}
+
+pub enum Assets {
+    // Loose files under the asset root.
+    Disk(PathBuf),
+    // Files packed in a .bbcart.
+    Cart(Cart),
+    // Files compiled into the executable.
+    #[cfg(all(feature = "embed-assets", not(debug_assertions)))]
+    Embedded,
+}
+
+impl Assets {
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        debug!(target: ASSETS, "Reading {}", name);
+        match self {
+            Assets::Disk(root) => {
+                let path = root.join(name);
+                std::fs::read(&path)
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e).into())
+            }
+            Assets::Cart(cart) => cart
+                .get(name)
+                .map(|bytes| bytes.to_vec())
+                .ok_or_else(|| format!("{} is not in the cart", name).into()),
+            #[cfg(all(feature = "embed-assets", not(debug_assertions)))]
+            Assets::Embedded => embedded::get(name)
+                .map(|bytes| bytes.to_vec())
+                .ok_or_else(|| format!("{} is not embedded", name).into()),
+        }
+    }
+
+    pub fn load_texture<'a>(
+        &self,
+        texture_creator: &'a TextureCreator<WindowContext>,
+        name: &str,
+    ) -> Result<Texture<'a>, Box<dyn Error>> {
+        debug!(target: ASSETS, "Loading texture {}", name);
+        match self {
+            Assets::Disk(root) => Ok(texture_creator.load_texture(root.join(name))?),
+            _ => Ok(texture_creator.load_texture_bytes(&self.read(name)?)?),
+        }
+    }
+
+    pub fn load_chunk(&self, name: &str) -> Result<Chunk, Box<dyn Error>> {
+        debug!(target: ASSETS, "Loading sound {}", name);
+        match self {
+            Assets::Disk(root) => Ok(Chunk::from_file(root.join(name))?),
+            _ => {
+                let bytes = self.read(name)?;
+                let rwops = RWops::from_bytes(&bytes)?;
+                let chunk = rwops.load_wav()?;
+                Ok(chunk)
+            }
+        }
+    }
+
+    pub fn load_music(&self, name: &str) -> Result<Music<'static>, Box<dyn Error>> {
+        debug!(target: ASSETS, "Loading music {}", name);
+        match self {
+            Assets::Disk(root) => Ok(Music::from_file(root.join(name))?),
+            // SDL_mixer streams music from the buffer for as long as it plays,
+            // so the bytes have to outlive the game.
+            Assets::Cart(_) => {
+                let bytes: &'static [u8] = Box::leak(self.read(name)?.into_boxed_slice());
+                Ok(Music::from_static_bytes(bytes)?)
+            }
+            #[cfg(all(feature = "embed-assets", not(debug_assertions)))]
+            Assets::Embedded => {
+                let bytes = embedded::get(name).ok_or_else(|| format!("{} is not embedded", name))?;
+                Ok(Music::from_static_bytes(bytes)?)
+            }
+        }
+    }
+}
@@ -0,0 +1,605 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::mixer::AudioSettings;
+use crate::p8;
+use crate::p8::{P8Cart, P8Music, P8Sfx};
+
+/*
+Sound goes through an audio backend, so the game runs the same with or without
+an audio device:
+
+    SdlAudio    plays through SDL_mixer (sdl_audio.rs)
+    NullAudio   plays nothing and records every sfx and music call, for
+                machines without audio and for tests
+
+The synth sfx and music patterns belong to the game rather than the backend,
+so they're passed in where a backend needs them. The backend owns the
+SoundLibrary, the metadata below and the sfx cooldowns.
+
+Every entry of audio.json can say how it's played, on top of its name:
+
+    volume          0.0..1.0, multiplied with the volume it's played at
+    loop_start      music only, seconds to go back to when the track loops
+    loop_end        music only, seconds at which it loops, the end if unset;
+                    checked once a frame, so it can loop a frame late
+    max_instances   how many channels the sfx can play on at once; another
+                    one replaces the oldest
+    priority        sfx with a higher priority take the channel of lower ones
+                    when every channel is busy, and never lose theirs to them
+    cooldown        milliseconds before the sfx can play again, others are
+                    ignored
+    groups          names to stop sounds together, e.g. "ui" or "footsteps"
+*/
+
+// Special values for sfx(), as in PICO-8. SFX_STOP stops the sound on a
+// channel (every sfx channel with CHANNEL_ANY) and SFX_RELEASE lets a looping
+// sound finish its current pass through the loop and stop.
+pub const SFX_STOP: &str = "-1";
+pub const SFX_RELEASE: &str = "-2";
+// CHANNEL_ANY plays on a free channel and CHANNEL_ALL stops the sound on every
+// channel it's playing on.
+pub const CHANNEL_ANY: i32 = -1;
+pub const CHANNEL_ALL: i32 = -2;
+// Stops the music when passed to music(), fading it out over `fadems`.
+pub const MUSIC_STOP: &str = "-1";
+
+// The project's audio.json.
+#[derive(Deserialize, Debug)]
+pub struct AudioConfig {
+    pub music: Vec<AudioEntry>,
+    pub sfx: Vec<AudioEntry>,
+    // Synthesized sfx, numbered in the order they're listed.
+    #[serde(default)]
+    pub synth: Vec<SynthEntry>,
+    // Music patterns of synth sfx, numbered in the order they're listed.
+    #[serde(default)]
+    pub patterns: Vec<SynthEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AudioEntry {
+    pub path: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub meta: SoundMeta,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SynthEntry {
+    pub name: String,
+    // A line in the format of a PICO-8 cart's __sfx__ section, or __music__
+    // for patterns.
+    pub data: String,
+    #[serde(flatten)]
+    pub meta: SoundMeta,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SoundMeta {
+    pub volume: f32,
+    pub loop_start: Option<f64>,
+    pub loop_end: Option<f64>,
+    pub max_instances: Option<u32>,
+    pub priority: i32,
+    pub cooldown: u32,
+    pub groups: Vec<String>,
+}
+
+impl Default for SoundMeta {
+    fn default() -> SoundMeta {
+        SoundMeta {
+            volume: 1.0,
+            loop_start: None,
+            loop_end: None,
+            max_instances: None,
+            priority: 0,
+            cooldown: 0,
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl SoundMeta {
+    fn validate(&self, music: bool) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.volume) {
+            return Err(format!("volume must be between 0.0 and 1.0, got {}", self.volume));
+        }
+        if !music && (self.loop_start.is_some() || self.loop_end.is_some()) {
+            return Err("loop_start and loop_end are only for recorded music".to_string());
+        }
+        let loop_start = self.loop_start.unwrap_or(0.0);
+        if loop_start < 0.0 {
+            return Err(format!("loop_start must be 0 or more seconds, got {}", loop_start));
+        }
+        if let Some(loop_end) = self.loop_end {
+            if loop_end <= loop_start {
+                return Err(format!("loop_end ({}) must be after loop_start ({})", loop_end, loop_start));
+            }
+        }
+        if self.max_instances == Some(0) {
+            return Err("max_instances must be at least 1".to_string());
+        }
+        if self.groups.iter().any(|group| group.is_empty()) {
+            return Err("group names can't be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl AudioConfig {
+    // Checks every entry's metadata, and that no two music entries or two
+    // sfx entries share a name.
+    pub fn validate(&self) -> Result<(), String> {
+        let music = self.music.iter().map(|e| ("music", &e.name, &e.meta));
+        let patterns = self.patterns.iter().map(|e| ("patterns", &e.name, &e.meta));
+        let sfx = self.sfx.iter().map(|e| ("sfx", &e.name, &e.meta));
+        let synth = self.synth.iter().map(|e| ("synth", &e.name, &e.meta));
+
+        let mut music_names = HashMap::new();
+        for (list, name, meta) in music.chain(patterns) {
+            meta.validate(list == "music")
+                .map_err(|e| format!("{} entry \"{}\": {}", list, name, e))?;
+            if let Some(other) = music_names.insert(name, list) {
+                return Err(format!("{} entry \"{}\": the name is already used in {}", list, name, other));
+            }
+        }
+        let mut sfx_names = HashMap::new();
+        for (list, name, meta) in sfx.chain(synth) {
+            meta.validate(false)
+                .map_err(|e| format!("{} entry \"{}\": {}", list, name, e))?;
+            if let Some(other) = sfx_names.insert(name, list) {
+                return Err(format!("{} entry \"{}\": the name is already used in {}", list, name, other));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The metadata of the sounds in the audio config by name, and when each sfx
+// last played for cooldowns. Music and sfx have their own names, like in
+// AudioConfig::validate.
+#[derive(Default)]
+pub struct SoundLibrary {
+    music: HashMap<String, SoundMeta>,
+    sfx: HashMap<String, SoundMeta>,
+    last_played: HashMap<String, Instant>,
+}
+
+impl SoundLibrary {
+    pub fn from_config(config: &AudioConfig) -> SoundLibrary {
+        let music = config
+            .music
+            .iter()
+            .map(|e| (e.name.clone(), e.meta.clone()))
+            .chain(config.patterns.iter().map(|e| (e.name.clone(), e.meta.clone())))
+            .collect();
+        let sfx = config
+            .sfx
+            .iter()
+            .map(|e| (e.name.clone(), e.meta.clone()))
+            .chain(config.synth.iter().map(|e| (e.name.clone(), e.meta.clone())))
+            .collect();
+        SoundLibrary {
+            music,
+            sfx,
+            last_played: HashMap::new(),
+        }
+    }
+
+    pub fn music_meta(&self, name: &str) -> Option<&SoundMeta> {
+        self.music.get(name)
+    }
+
+    pub fn sfx_meta(&self, name: &str) -> Option<&SoundMeta> {
+        self.sfx.get(name)
+    }
+
+    pub fn music_volume(&self, name: &str) -> f32 {
+        self.music_meta(name).map_or(1.0, |meta| meta.volume)
+    }
+
+    pub fn sfx_volume(&self, name: &str) -> f32 {
+        self.sfx_meta(name).map_or(1.0, |meta| meta.volume)
+    }
+
+    pub fn priority(&self, name: &str) -> i32 {
+        self.sfx_meta(name).map_or(0, |meta| meta.priority)
+    }
+
+    pub fn music_in_group(&self, name: &str, group: &str) -> bool {
+        self.music_meta(name).is_some_and(|meta| meta.groups.iter().any(|g| g == group))
+    }
+
+    pub fn sfx_in_group(&self, name: &str, group: &str) -> bool {
+        self.sfx_meta(name).is_some_and(|meta| meta.groups.iter().any(|g| g == group))
+    }
+
+    // Whether an sfx is past its cooldown at `now`.
+    pub fn cooldown_ready(&self, name: &str, now: Instant) -> bool {
+        let cooldown = self.sfx_meta(name).map_or(0, |meta| meta.cooldown);
+        match self.last_played.get(name) {
+            Some(last) => now.saturating_duration_since(*last) >= Duration::from_millis(cooldown as u64),
+            None => true,
+        }
+    }
+
+    // Starts an sfx's cooldown, once it got a channel to play on.
+    pub fn played(&mut self, name: &str, now: Instant) {
+        if self.sfx_meta(name).is_some_and(|meta| meta.cooldown > 0) {
+            self.last_played.insert(name.to_string(), now);
+        }
+    }
+}
+
+// How an sfx is played: see BBMicroApi::sfx_with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SfxParams {
+    pub channel: i32,
+    pub offset: u32,
+    pub length: u32,
+    pub volume: f32,
+    pub pan: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioCall {
+    Sfx { name: String, params: SfxParams },
+    Music { name: String, fadems: u32, channelmask: u32 },
+}
+
+// The synth sfx and music patterns, and the names the audio config gives them.
+pub struct SynthBank {
+    pub sfx: Vec<P8Sfx>,
+    pub sfx_names: HashMap<String, usize>,
+    pub patterns: Vec<P8Music>,
+    pub pattern_names: HashMap<String, usize>,
+}
+
+impl SynthBank {
+    pub fn new() -> SynthBank {
+        SynthBank {
+            sfx: vec![P8Sfx::from_line("").unwrap(); p8::SFX_COUNT],
+            sfx_names: HashMap::new(),
+            patterns: vec![P8Music::from_line("").unwrap(); p8::MUSIC_COUNT],
+            pattern_names: HashMap::new(),
+        }
+    }
+
+    // The synth sfx and patterns listed in the audio config, numbered in order.
+    pub fn from_config(config: &AudioConfig) -> Result<SynthBank, Box<dyn Error>> {
+        if config.synth.len() > p8::SFX_COUNT {
+            return Err(format!("At most {} synth sfx are allowed", p8::SFX_COUNT).into());
+        }
+        if config.patterns.len() > p8::MUSIC_COUNT {
+            return Err(format!("At most {} music patterns are allowed", p8::MUSIC_COUNT).into());
+        }
+
+        let mut bank = SynthBank::new();
+        for (n, entry) in config.synth.iter().enumerate() {
+            bank.sfx[n] = P8Sfx::from_line(&entry.data)
+                .map_err(|e| format!("Could not parse synth sfx {}: {}", entry.name, e))?;
+            bank.sfx_names.insert(entry.name.clone(), n);
+        }
+        for (n, entry) in config.patterns.iter().enumerate() {
+            bank.patterns[n] = P8Music::from_line(&entry.data)
+                .map_err(|e| format!("Could not parse music pattern {}: {}", entry.name, e))?;
+            bank.pattern_names.insert(entry.name.clone(), n);
+        }
+        Ok(bank)
+    }
+
+    // Replaces the sfx and patterns with a cart's.
+    pub fn import(&mut self, cart: &P8Cart) {
+        for n in 0..p8::SFX_COUNT {
+            self.sfx[n] = cart.sfx(n);
+        }
+        for n in 0..p8::MUSIC_COUNT {
+            self.patterns[n] = cart.music(n);
+        }
+    }
+
+    // The sfx a name refers to, by its name in the audio config or its number.
+    pub fn sfx_number(&self, name: &str) -> Option<usize> {
+        match self.sfx_names.get(name) {
+            Some(n) => Some(*n),
+            None => name.parse().ok().filter(|n| *n < self.sfx.len()),
+        }
+    }
+
+    // The pattern a name refers to, the same way.
+    pub fn pattern_number(&self, name: &str) -> Option<usize> {
+        match self.pattern_names.get(name) {
+            Some(n) => Some(*n),
+            None => name.parse().ok().filter(|n| *n < self.patterns.len()),
+        }
+    }
+}
+
+pub trait AudioBackend {
+    // Plays, stops or releases an sfx, see BBMicroApi::sfx_with. Returns the
+    // channel a sound started on.
+    fn sfx(&mut self, synth: &SynthBank, name: &str, params: SfxParams) -> Option<i32>;
+    // Plays or stops music, see BBMicroApi::music.
+    fn music(&mut self, synth: &SynthBank, name: &str, fadems: u32, channelmask: u32);
+    // Called once a frame.
+    fn update(&mut self, synth: &SynthBank);
+    fn apply_settings(&mut self, settings: AudioSettings);
+    // Changes the volume and pan of the sfx playing on a channel.
+    fn set_sfx_mix(&mut self, channel: i32, volume: f32, pan: f32);
+    // The synth sfx or patterns changed, so anything made from them is stale.
+    fn synth_changed(&mut self);
+
+    fn sfx_playing(&self, channel: i32) -> Option<&str>;
+    fn sfx_note(&self, synth: &SynthBank, channel: i32) -> Option<usize>;
+    fn music_playing(&self) -> Option<&str>;
+
+    // The metadata of the audio config's sounds, and the sfx cooldowns.
+    fn sounds(&self) -> &SoundLibrary;
+    fn sounds_mut(&mut self) -> &mut SoundLibrary;
+
+    // The sfx and music calls so far, for backends that record them.
+    fn calls(&self) -> &[AudioCall] {
+        &[]
+    }
+}
+
+// A backend that plays nothing. Music counts as playing from the call that
+// starts it to the one that stops it.
+#[derive(Default)]
+pub struct NullAudio {
+    calls: Vec<AudioCall>,
+    music: Option<String>,
+    sounds: SoundLibrary,
+}
+
+impl NullAudio {
+    pub fn new(config: &AudioConfig) -> NullAudio {
+        NullAudio {
+            sounds: SoundLibrary::from_config(config),
+            ..NullAudio::default()
+        }
+    }
+
+    pub fn clear_calls(&mut self) {
+        self.calls.clear();
+    }
+}
+
+impl AudioBackend for NullAudio {
+    fn sfx(&mut self, _synth: &SynthBank, name: &str, params: SfxParams) -> Option<i32> {
+        self.calls.push(AudioCall::Sfx {
+            name: name.to_string(),
+            params,
+        });
+        None
+    }
+
+    fn music(&mut self, _synth: &SynthBank, name: &str, fadems: u32, channelmask: u32) {
+        self.calls.push(AudioCall::Music {
+            name: name.to_string(),
+            fadems,
+            channelmask,
+        });
+        self.music = if name == MUSIC_STOP { None } else { Some(name.to_string()) };
+    }
+
+    fn update(&mut self, _synth: &SynthBank) {}
+
+    fn apply_settings(&mut self, _settings: AudioSettings) {}
+
+    fn set_sfx_mix(&mut self, _channel: i32, _volume: f32, _pan: f32) {}
+
+    fn synth_changed(&mut self) {}
+
+    fn sfx_playing(&self, _channel: i32) -> Option<&str> {
+        None
+    }
+
+    fn sfx_note(&self, _synth: &SynthBank, _channel: i32) -> Option<usize> {
+        None
+    }
+
+    fn music_playing(&self) -> Option<&str> {
+        self.music.as_deref()
+    }
+
+    fn sounds(&self) -> &SoundLibrary {
+        &self.sounds
+    }
+
+    fn sounds_mut(&mut self) -> &mut SoundLibrary {
+        &mut self.sounds
+    }
+
+    fn calls(&self) -> &[AudioCall] {
+        &self.calls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banks_name_and_number_entries() {
+        let config: AudioConfig = serde_json::from_str(
+            r#"{
+                "music": [],
+                "sfx": [],
+                "synth": [
+                    { "name": "blip", "data": "" },
+                    { "name": "jump", "data": "000800002405024050300503005000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000" }
+                ],
+                "patterns": [{ "name": "title", "data": "01 00014243" }]
+            }"#,
+        )
+        .unwrap();
+        let bank = SynthBank::from_config(&config).unwrap();
+
+        assert_eq!(bank.sfx_number("jump"), Some(1));
+        assert_eq!(bank.sfx_number("5"), Some(5));
+        assert_eq!(bank.sfx_number("64"), None);
+        assert_eq!(bank.sfx_number("boom"), None);
+        assert_eq!(bank.sfx[1].speed, 8);
+        assert!(bank.sfx[0].is_empty());
+
+        assert_eq!(bank.pattern_number("title"), Some(0));
+        assert_eq!(bank.patterns[0].channels, [Some(0), Some(1), None, None]);
+        assert!(bank.patterns[0].loop_start);
+        assert!(bank.patterns[1].is_empty());
+    }
+
+    #[test]
+    fn imports_p8_sfx_and_music() {
+        let sfx = format!("00100008{:0<160}", "1805024050");
+        let text = format!(
+            "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__sfx__\n{}\n{}\n__music__\n01 00014344\n",
+            sfx, sfx
+        );
+        let cart = P8Cart::from_p8_text(&text).unwrap();
+        let mut bank = SynthBank::new();
+        bank.import(&cart);
+
+        // What import_p8 hands the synth is what sfx() and music() play.
+        assert_eq!(bank.sfx[0], cart.sfx(0));
+        assert_eq!(bank.sfx[1].notes[1].pitch, 0x24);
+        assert_eq!(bank.sfx_number("1"), Some(1));
+        assert!(!crate::synth::render_sfx(&bank.sfx, 1, 8000, 0, None).intro.is_empty());
+
+        assert_eq!(bank.patterns[0].channels, [Some(0), Some(1), None, None]);
+        assert!(bank.patterns[0].loop_start);
+        assert!(bank.patterns[1].is_empty());
+        assert!(!crate::sequencer::render_music(&bank.sfx, &bank.patterns, 0, 8000).intro.is_empty());
+    }
+
+    #[test]
+    fn bad_entries_are_errors() {
+        let config: AudioConfig = serde_json::from_str(
+            r#"{ "music": [], "sfx": [], "patterns": [{ "name": "broken", "data": "zz" }] }"#,
+        )
+        .unwrap();
+        let err = SynthBank::from_config(&config).err().unwrap();
+        assert!(err.to_string().contains("broken"), "{}", err);
+    }
+
+    #[test]
+    fn validates_metadata() {
+        let config = |json: &str| -> AudioConfig { serde_json::from_str(json).unwrap() };
+        let ok = config(
+            r#"{
+                "music": [{ "name": "bgm", "path": "music.mp3", "volume": 0.5, "loop_start": 2.5, "loop_end": 30 }],
+                "sfx": [{ "name": "step", "path": "step.wav", "max_instances": 2, "cooldown": 80, "groups": ["footsteps"] }],
+                "synth": [{ "name": "bgm", "data": "", "priority": 3 }]
+            }"#,
+        );
+        assert_eq!(ok.validate(), Ok(()));
+        assert_eq!(ok.music[0].meta.loop_end, Some(30.0));
+        assert_eq!(ok.sfx[0].meta.volume, 1.0);
+        assert_eq!(ok.synth[0].meta.priority, 3);
+
+        // The music and the synth sfx named bgm keep their own metadata.
+        let library = SoundLibrary::from_config(&ok);
+        assert_eq!(library.music_volume("bgm"), 0.5);
+        assert_eq!(library.music_meta("bgm").unwrap().loop_start, Some(2.5));
+        assert_eq!(library.music_meta("bgm").unwrap().priority, 0);
+        assert_eq!(library.sfx_volume("bgm"), 1.0);
+        assert_eq!(library.sfx_meta("bgm").unwrap().loop_end, None);
+        assert_eq!(library.priority("bgm"), 3);
+        assert!(library.music_meta("step").is_none());
+
+        let err = |json: &str| config(json).validate().unwrap_err();
+        assert_eq!(
+            err(r#"{ "music": [], "sfx": [{ "name": "boom", "path": "boom.wav", "volume": 1.5 }] }"#),
+            "sfx entry \"boom\": volume must be between 0.0 and 1.0, got 1.5"
+        );
+        assert_eq!(
+            err(r#"{ "music": [{ "name": "bgm", "path": "m.mp3", "loop_start": 10, "loop_end": 4 }], "sfx": [] }"#),
+            "music entry \"bgm\": loop_end (4) must be after loop_start (10)"
+        );
+        assert_eq!(
+            err(r#"{ "music": [], "sfx": [], "synth": [{ "name": "hum", "data": "", "loop_end": 4 }] }"#),
+            "synth entry \"hum\": loop_start and loop_end are only for recorded music"
+        );
+        assert_eq!(
+            err(r#"{ "music": [], "sfx": [{ "name": "hit", "path": "hit.wav", "max_instances": 0 }] }"#),
+            "sfx entry \"hit\": max_instances must be at least 1"
+        );
+        assert_eq!(
+            err(r#"{ "music": [], "sfx": [{ "name": "hit", "path": "a.wav" }], "synth": [{ "name": "hit", "data": "" }] }"#),
+            "synth entry \"hit\": the name is already used in sfx"
+        );
+    }
+
+    #[test]
+    fn cooldowns_and_groups() {
+        let config: AudioConfig = serde_json::from_str(
+            r#"{ "music": [], "sfx": [{ "name": "shot", "path": "shot.wav", "cooldown": 100, "volume": 0.25, "groups": ["weapons"] }] }"#,
+        )
+        .unwrap();
+        let mut library = SoundLibrary::from_config(&config);
+        let start = Instant::now();
+
+        // Only a sound that played starts its cooldown.
+        assert!(library.cooldown_ready("shot", start));
+        assert!(library.cooldown_ready("shot", start + Duration::from_millis(10)));
+        library.played("shot", start + Duration::from_millis(10));
+        assert!(!library.cooldown_ready("shot", start + Duration::from_millis(109)));
+        assert!(library.cooldown_ready("shot", start + Duration::from_millis(110)));
+        library.played("shot", start + Duration::from_millis(110));
+        assert!(!library.cooldown_ready("shot", start + Duration::from_millis(150)));
+        library.played("other", start);
+        assert!(library.cooldown_ready("other", start));
+
+        assert_eq!(library.sfx_volume("shot"), 0.25);
+        assert_eq!(library.sfx_volume("other"), 1.0);
+        assert!(library.sfx_in_group("shot", "weapons"));
+        assert!(!library.sfx_in_group("shot", "ui"));
+        assert!(!library.music_in_group("shot", "weapons"));
+    }
+
+    #[test]
+    fn null_audio_records_calls() {
+        let bank = SynthBank::new();
+        let config: AudioConfig = serde_json::from_str(r#"{ "music": [], "sfx": [] }"#).unwrap();
+        let mut audio = NullAudio::new(&config);
+        let params = SfxParams {
+            channel: CHANNEL_ANY,
+            offset: 0,
+            length: 0,
+            volume: 1.0,
+            pan: 0.0,
+        };
+        assert_eq!(audio.sfx(&bank, "jump", params), None);
+        audio.music(&bank, "title", 500, 0b1000);
+        assert_eq!(audio.music_playing(), Some("title"));
+        audio.music(&bank, MUSIC_STOP, 250, 0);
+        assert_eq!(audio.music_playing(), None);
+
+        assert_eq!(
+            audio.calls(),
+            &[
+                AudioCall::Sfx {
+                    name: "jump".to_string(),
+                    params,
+                },
+                AudioCall::Music {
+                    name: "title".to_string(),
+                    fadems: 500,
+                    channelmask: 0b1000,
+                },
+                AudioCall::Music {
+                    name: MUSIC_STOP.to_string(),
+                    fadems: 250,
+                    channelmask: 0,
+                },
+            ]
+        );
+        audio.clear_calls();
+        assert!(audio.calls().is_empty());
+    }
+}
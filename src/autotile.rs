@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tilemap::{MapAddressing, TileMap};
+
+/*
+Autotiling picks the sprite of a terrain cell from which of its neighbors are
+the same terrain, so edges and corners follow the shape that was painted.
+
+A terrain looks at either 4 or 8 neighbors and builds a mask from them:
+
+    4 neighbors    N 1, E 2, S 4, W 8
+    8 neighbors    N 1, NE 2, E 4, SE 8, S 16, SW 32, W 64, NW 128
+
+With 8 neighbors a corner only counts when both edges next to it do too, so
+the 256 masks come down to the 47 shapes a tileset actually needs. The rules
+map masks to sprites and any mask without a rule uses the default sprite.
+
+A cell is part of a terrain when it holds any of the terrain's sprites (its
+default, the sprites in its rules and any extra members), so re-resolving a
+cell never changes which cells count as terrain.
+*/
+
+const N: u8 = 1;
+const E: u8 = 2;
+const S: u8 = 4;
+const W: u8 = 8;
+
+const N8: u8 = 1;
+const NE8: u8 = 2;
+const E8: u8 = 4;
+const SE8: u8 = 8;
+const S8: u8 = 16;
+const SW8: u8 = 32;
+const W8: u8 = 64;
+const NW8: u8 = 128;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Terrain {
+    pub name: String,
+    // 4 or 8.
+    pub neighbors: u8,
+    // Mask to sprite.
+    pub rules: BTreeMap<u8, u8>,
+    // Used when no rule matches the mask.
+    pub default: u8,
+    // Other sprites that count as this terrain for its neighbors.
+    #[serde(default)]
+    pub members: Vec<u8>,
+    // Whether cells off the edge of the map count as this terrain.
+    #[serde(default)]
+    pub edges_match: bool,
+}
+
+impl Terrain {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.neighbors != 4 && self.neighbors != 8 {
+            return Err(format!(
+                "Terrain {} has neighbors {}, expected 4 or 8",
+                self.name, self.neighbors
+            ));
+        }
+        if self.neighbors == 4 {
+            if let Some(mask) = self.rules.keys().find(|mask| **mask > 15) {
+                return Err(format!(
+                    "Terrain {} has a rule for mask {}, 4 neighbor masks go up to 15",
+                    self.name, mask
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, sprite: u8) -> bool {
+        sprite == self.default || self.rules.values().any(|s| *s == sprite) || self.members.contains(&sprite)
+    }
+
+    // The mask of a cell, `is_terrain` tells whether the cell at an offset
+    // from it is this terrain.
+    pub fn mask(&self, is_terrain: impl Fn(i32, i32) -> bool) -> u8 {
+        if self.neighbors == 4 {
+            let mut mask = 0;
+            for (bit, dx, dy) in [(N, 0, -1), (E, 1, 0), (S, 0, 1), (W, -1, 0)] {
+                if is_terrain(dx, dy) {
+                    mask |= bit;
+                }
+            }
+            return mask;
+        }
+
+        let mut mask = 0;
+        for (bit, dx, dy) in [(N8, 0, -1), (E8, 1, 0), (S8, 0, 1), (W8, -1, 0)] {
+            if is_terrain(dx, dy) {
+                mask |= bit;
+            }
+        }
+        for (bit, edges, dx, dy) in [
+            (NE8, N8 | E8, 1, -1),
+            (SE8, S8 | E8, 1, 1),
+            (SW8, S8 | W8, -1, 1),
+            (NW8, N8 | W8, -1, -1),
+        ] {
+            if mask & edges == edges && is_terrain(dx, dy) {
+                mask |= bit;
+            }
+        }
+        mask
+    }
+
+    pub fn sprite(&self, mask: u8) -> u8 {
+        self.rules.get(&mask).copied().unwrap_or(self.default)
+    }
+}
+
+// Whether a cell is part of the terrain, going by its addressing and the
+// terrain's edge setting off the map.
+fn is_terrain(map: &TileMap, terrain: &Terrain, x: i32, y: i32, layer: usize, addressing: MapAddressing) -> bool {
+    match map.sample(x, y, layer, addressing) {
+        Some(sprite) => terrain.contains(sprite),
+        None => terrain.edges_match,
+    }
+}
+
+// Picks the sprite for a terrain cell from its neighbors. Returns the cell if
+// it changed.
+pub fn resolve_cell(
+    map: &mut TileMap,
+    terrain: &Terrain,
+    x: i32,
+    y: i32,
+    layer: usize,
+    addressing: MapAddressing,
+) -> Option<(u32, u32)> {
+    let (cx, cy) = map.resolve(x, y, addressing)?;
+    let current = map.get(cx, cy, layer)?;
+    if !terrain.contains(current) {
+        return None;
+    }
+
+    let mask = terrain.mask(|dx, dy| is_terrain(map, terrain, x + dx, y + dy, layer, addressing));
+    let sprite = terrain.sprite(mask);
+    if sprite == current {
+        return None;
+    }
+    map.set(cx, cy, layer, sprite);
+    Some((cx, cy))
+}
+
+// Re-resolves the terrain cells around (x, y) and the cell itself. Returns
+// the cells that changed.
+pub fn retile(
+    map: &mut TileMap,
+    terrain: &Terrain,
+    x: i32,
+    y: i32,
+    layer: usize,
+    addressing: MapAddressing,
+) -> Vec<(u32, u32)> {
+    let mut changed = Vec::new();
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            changed.extend(resolve_cell(map, terrain, x + dx, y + dy, layer, addressing));
+        }
+    }
+    changed
+}
+
+// Paints a terrain cell and fixes up its neighbors. Returns the cells that
+// changed, empty if (x, y) is outside the map.
+pub fn place(
+    map: &mut TileMap,
+    terrain: &Terrain,
+    x: i32,
+    y: i32,
+    layer: usize,
+    addressing: MapAddressing,
+) -> Vec<(u32, u32)> {
+    erase(map, terrain, x, y, layer, terrain.default, addressing)
+}
+
+// Sets a cell to `sprite`, terrain or not, and fixes up its neighbors.
+pub fn erase(
+    map: &mut TileMap,
+    terrain: &Terrain,
+    x: i32,
+    y: i32,
+    layer: usize,
+    sprite: u8,
+    addressing: MapAddressing,
+) -> Vec<(u32, u32)> {
+    let (cx, cy) = match map.resolve(x, y, addressing) {
+        Some(cell) => cell,
+        None => return Vec::new(),
+    };
+    if !map.set(cx, cy, layer, sprite) {
+        return Vec::new();
+    }
+
+    let mut changed = vec![(cx, cy)];
+    for cell in retile(map, terrain, x, y, layer, addressing) {
+        if !changed.contains(&cell) {
+            changed.push(cell);
+        }
+    }
+    changed
+}
+
+// Resolves every terrain cell of a layer, for maps that were painted with
+// plain sprites or imported from elsewhere. Returns how many cells changed.
+pub fn retile_layer(map: &mut TileMap, terrain: &Terrain, layer: usize, addressing: MapAddressing) -> usize {
+    let mut changed = 0;
+    for y in 0..map.height as i32 {
+        for x in 0..map.width as i32 {
+            if resolve_cell(map, terrain, x, y, layer, addressing).is_some() {
+                changed += 1;
+            }
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 4 neighbor water terrain: 1 is a lone pool, 2..=16 the masks 1..=15.
+    fn water() -> Terrain {
+        Terrain {
+            name: "water".to_string(),
+            neighbors: 4,
+            rules: (1..16).map(|mask| (mask, mask + 1)).collect(),
+            default: 1,
+            members: Vec::new(),
+            edges_match: false,
+        }
+    }
+
+    #[test]
+    fn four_neighbor_masks() {
+        let terrain = water();
+        assert_eq!(terrain.mask(|_, _| false), 0);
+        assert_eq!(terrain.mask(|_, _| true), 15);
+        assert_eq!(terrain.mask(|dx, dy| (dx, dy) == (0, -1)), N);
+        assert_eq!(terrain.mask(|dx, dy| (dx, dy) == (-1, 0)), W);
+    }
+
+    #[test]
+    fn eight_neighbor_corners_need_both_edges() {
+        let terrain = Terrain {
+            neighbors: 8,
+            ..water()
+        };
+        assert_eq!(terrain.mask(|_, _| true), 255);
+        // A corner on its own doesn't count.
+        assert_eq!(terrain.mask(|dx, dy| (dx, dy) == (1, -1)), 0);
+        assert_eq!(terrain.mask(|dx, dy| dy <= 0 && dx >= 0), N8 | NE8 | E8);
+        assert_eq!(terrain.mask(|dx, dy| dy == -1 || (dx, dy) == (1, 0)), N8 | NE8 | E8);
+    }
+
+    #[test]
+    fn placing_resolves_the_cell_and_its_neighbors() {
+        let terrain = water();
+        let mut map = TileMap::new(4, 4, 1);
+
+        let changed = place(&mut map, &terrain, 1, 1, 0, MapAddressing::Bounded);
+        assert_eq!(changed, vec![(1, 1)]);
+        assert_eq!(map.get(1, 1, 0), Some(1));
+
+        place(&mut map, &terrain, 2, 1, 0, MapAddressing::Bounded);
+        assert_eq!(map.get(1, 1, 0), Some(terrain.sprite(E)));
+        assert_eq!(map.get(2, 1, 0), Some(terrain.sprite(W)));
+
+        place(&mut map, &terrain, 1, 2, 0, MapAddressing::Bounded);
+        assert_eq!(map.get(1, 1, 0), Some(terrain.sprite(E | S)));
+        assert_eq!(map.get(1, 2, 0), Some(terrain.sprite(N)));
+
+        let changed = erase(&mut map, &terrain, 1, 1, 0, 0, MapAddressing::Bounded);
+        assert_eq!(changed.len(), 3);
+        assert_eq!(map.get(2, 1, 0), Some(1));
+        assert_eq!(map.get(1, 2, 0), Some(1));
+    }
+
+    #[test]
+    fn edges_follow_the_terrain_setting() {
+        let mut terrain = water();
+        let mut map = TileMap::new(2, 1, 1);
+        place(&mut map, &terrain, 0, 0, 0, MapAddressing::Bounded);
+        assert_eq!(map.get(0, 0, 0), Some(1));
+
+        terrain.edges_match = true;
+        retile_layer(&mut map, &terrain, 0, MapAddressing::Bounded);
+        assert_eq!(map.get(0, 0, 0), Some(terrain.sprite(N | S | W)));
+
+        // With wrapping, the neighbors off the edge are on the map.
+        terrain.edges_match = false;
+        retile_layer(&mut map, &terrain, 0, MapAddressing::Wrap);
+        assert_eq!(map.get(0, 0, 0), Some(terrain.sprite(N | S)));
+    }
+
+    #[test]
+    fn retiling_a_layer_resolves_painted_cells() {
+        let terrain = water();
+        let mut map = TileMap::new(3, 1, 1);
+        for x in 0..3 {
+            map.set(x, 0, 0, 1);
+        }
+        assert_eq!(retile_layer(&mut map, &terrain, 0, MapAddressing::Bounded), 3);
+        assert_eq!(map.get(0, 0, 0), Some(terrain.sprite(E)));
+        assert_eq!(map.get(1, 0, 0), Some(terrain.sprite(E | W)));
+        assert_eq!(map.get(2, 0, 0), Some(terrain.sprite(W)));
+        assert_eq!(retile_layer(&mut map, &terrain, 0, MapAddressing::Bounded), 0);
+    }
+
+    #[test]
+    fn validation() {
+        assert!(water().validate().is_ok());
+        let bad = Terrain {
+            neighbors: 6,
+            ..water()
+        };
+        assert!(bad.validate().is_err());
+        let mut bad = water();
+        bad.rules.insert(16, 3);
+        assert!(bad.validate().is_err());
+    }
+}
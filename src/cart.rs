@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path};
+
+use crate::manifest::{Manifest, Project, MANIFEST_NAME};
+
+/*
+A .bbcart packs a whole project into one file:
+
+    magic     "BBCART"
+    version   u16
+    count     u32
+    count x {
+        name_len  u16
+        name      utf-8, relative to the asset root
+        data_len  u32
+        data
+    }
+
+All integers are little-endian. The manifest is stored as bbmicro.json with
+asset_root rewritten to ".", every other entry is a file it names: the
+spritesheet, sprite flags, maps, font, audio config and the sounds that
+config lists. The palette lives in the manifest itself.
+*/
+
+pub const CART_MAGIC: &[u8; 6] = b"BBCART";
+pub const CART_VERSION: u16 = 1;
+pub const CART_EXTENSION: &str = "bbcart";
+
+pub struct Cart {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Cart {
+    pub fn new() -> Cart {
+        Cart {
+            files: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, data: Vec<u8>) {
+        self.files.insert(name.to_string(), data);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.files.get(name).map(|data| data.as_slice())
+    }
+
+    pub fn manifest(&self) -> Result<Manifest, Box<dyn Error>> {
+        let bytes = self
+            .get(MANIFEST_NAME)
+            .ok_or_else(|| format!("Cart has no {}", MANIFEST_NAME))?;
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Cart, Box<dyn Error>> {
+        let mut magic = [0; 6];
+        reader.read_exact(&mut magic)?;
+        if &magic != CART_MAGIC {
+            return Err("Not a bbcart file".into());
+        }
+
+        let version = read_u16(reader)?;
+        if version > CART_VERSION {
+            return Err(format!(
+                "Cart version {} is newer than the supported version {}",
+                version, CART_VERSION
+            )
+            .into());
+        }
+
+        let mut cart = Cart::new();
+        let count = read_u32(reader)?;
+        for _ in 0..count {
+            let name_len = read_u16(reader)? as usize;
+            let mut name = vec![0; name_len];
+            reader.read_exact(&mut name)?;
+            let name = String::from_utf8(name)?;
+
+            // Read what's there rather than trusting the length, so a bad
+            // header can't ask for a huge allocation up front.
+            let data_len = read_u32(reader)?;
+            let mut data = Vec::new();
+            reader.by_ref().take(data_len as u64).read_to_end(&mut data)?;
+            if data.len() != data_len as usize {
+                return Err(format!("{} is truncated, {} of {} bytes", name, data.len(), data_len).into());
+            }
+
+            cart.files.insert(name, data);
+        }
+
+        Ok(cart)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        writer.write_all(CART_MAGIC)?;
+        writer.write_all(&CART_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.files.len() as u32).to_le_bytes())?;
+        for (name, data) in self.files.iter() {
+            if name.len() > u16::MAX as usize || data.len() > u32::MAX as usize {
+                return Err(format!("{} is too large for a cart", name).into());
+            }
+            writer.write_all(&(name.len() as u16).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_all(&(data.len() as u32).to_le_bytes())?;
+            writer.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Cart, Box<dyn Error>> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        Cart::read(&mut BufReader::new(file))
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e).into())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Packs the project at `path` (a manifest or the directory holding one).
+    pub fn pack<P: AsRef<Path>>(path: P) -> Result<Cart, Box<dyn Error>> {
+        let project = Project::load(path)?;
+
+        let mut cart = Cart::new();
+        for name in project.manifest.files(&project.assets)? {
+            cart.insert(&name, project.assets.read(&name)?);
+        }
+
+        let mut manifest = project.manifest;
+        manifest.asset_root = ".".to_string();
+        cart.insert(MANIFEST_NAME, serde_json::to_vec_pretty(&manifest)?);
+
+        Ok(cart)
+    }
+
+    // Writes every file in the cart under `dir`, giving a project that can be
+    // run with --project or packed again.
+    pub fn unpack<P: AsRef<Path>>(&self, dir: P) -> Result<(), Box<dyn Error>> {
+        let dir = dir.as_ref();
+        for (name, data) in self.files.iter() {
+            let rel = Path::new(name);
+            if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(format!("Refusing to unpack {} outside of {}", name, dir.display()).into());
+            }
+
+            let path = dir.join(rel);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, data)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, Box<dyn Error>> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Box<dyn Error>> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(cart: &Cart) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        cart.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut cart = Cart::new();
+        cart.insert("bbmicro.json", b"{}".to_vec());
+        cart.insert("maps/level1.bbmap", vec![1, 2, 3]);
+        cart.insert("empty.json", Vec::new());
+
+        let read = Cart::read(&mut bytes(&cart).as_slice()).unwrap();
+        assert_eq!(read.files, cart.files);
+        assert_eq!(read.get("maps/level1.bbmap"), Some(&[1, 2, 3][..]));
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let err = |bytes: &[u8]| Cart::read(&mut &bytes[..]).err().unwrap().to_string();
+
+        assert_eq!(err(b"NOTCRT\x01\x00\x00\x00\x00\x00"), "Not a bbcart file");
+        assert!(err(b"BBCART\x09\x00\x00\x00\x00\x00").contains("newer"));
+
+        let mut cart = Cart::new();
+        cart.insert("sprites.png", vec![7; 100]);
+        let full = bytes(&cart);
+        assert!(err(&full[..full.len() - 10]).contains("truncated"));
+        assert!(Cart::read(&mut &full[..8]).is_err());
+
+        // A length far past the end of the file is an error, not an allocation.
+        let mut huge = full[..full.len() - 104].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(err(&huge).contains("truncated"));
+    }
+
+    #[test]
+    fn unpack_stays_in_its_directory() {
+        let dir = std::env::temp_dir().join(format!("bbmicro_unpack_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut cart = Cart::new();
+        cart.insert("../escape.txt", b"no".to_vec());
+        assert!(cart.unpack(&dir).is_err());
+        assert!(!dir.join("../escape.txt").exists());
+
+        let mut cart = Cart::new();
+        cart.insert("maps/a.bbmap", b"ok".to_vec());
+        cart.unpack(&dir).unwrap();
+        assert_eq!(std::fs::read(dir.join("maps/a.bbmap")).unwrap(), b"ok");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,584 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tilemap::{MapAddressing, TileMap};
+
+/*
+Collision against the tiles of a map layer, going by sprite flags.
+
+A tile with the solid flag blocks from every side. A one way tile only blocks
+things moving down onto its top, so they can jump up through it. Slope tiles
+have a floor running corner to corner across the tile: "up" rises to the
+right (/) and "down" falls to the right (\). Slopes hold up the bottom middle
+of a box and never block sideways.
+
+Moves are done one axis at a time, x then y, and each one sweeps every cell
+between the start and the end so fast moves can't pass through thin walls.
+*/
+
+const CELL: f32 = 8.0;
+const EPSILON: f32 = 0.001;
+
+// Which sprite flags mean what, and the layer to collide with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct CollisionConfig {
+    pub layer: u8,
+    pub solid: u8,
+    pub one_way: u8,
+    pub slope_up: u8,
+    pub slope_down: u8,
+}
+
+impl Default for CollisionConfig {
+    fn default() -> CollisionConfig {
+        CollisionConfig {
+            layer: 0,
+            solid: 0,
+            one_way: 1,
+            slope_up: 2,
+            slope_down: 3,
+        }
+    }
+}
+
+impl CollisionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let flags = [
+            ("solid", self.solid),
+            ("one_way", self.one_way),
+            ("slope_up", self.slope_up),
+            ("slope_down", self.slope_down),
+        ];
+        for (name, flag) in &flags {
+            if *flag > 7 {
+                return Err(format!("collision.{} must be a sprite flag from 0 to 7, got {}", name, flag));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether flag f is set in a sprite's flags. There are 8 flags, so higher
+// ones are never set.
+pub fn has_flag(flags: u8, f: u8) -> bool {
+    f < 8 && flags & (1 << f) != 0
+}
+
+// An axis aligned box in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+impl Aabb {
+    pub fn new(x: f32, y: f32, w: f32, h: f32) -> Aabb {
+        Aabb { x, y, w, h }
+    }
+
+    pub fn right(&self) -> f32 {
+        self.x + self.w
+    }
+
+    pub fn bottom(&self) -> f32 {
+        self.y + self.h
+    }
+}
+
+// A tile that stopped a move. The normal points away from the tile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+    pub cell_x: i32,
+    pub cell_y: i32,
+    pub sprite: u8,
+    pub normal: (f32, f32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movement {
+    // Where the box ended up.
+    pub rect: Aabb,
+    pub contacts: Vec<Contact>,
+}
+
+impl Movement {
+    // Standing on something, flat or sloped.
+    pub fn on_floor(&self) -> bool {
+        self.contacts.iter().any(|c| c.normal.1 < 0.0)
+    }
+
+    pub fn on_ceiling(&self) -> bool {
+        self.contacts.iter().any(|c| c.normal.1 > 0.0)
+    }
+
+    pub fn on_wall(&self) -> bool {
+        self.contacts.iter().any(|c| c.normal.1 == 0.0 && c.normal.0 != 0.0)
+    }
+}
+
+pub struct TileCollider<'m> {
+    pub map: &'m TileMap,
+    pub flags: &'m [u8; 256],
+    pub config: CollisionConfig,
+    pub addressing: MapAddressing,
+}
+
+// The cells a span of pixels start..end covers, end exclusive.
+fn cell_span(start: f32, end: f32) -> (i32, i32) {
+    ((start / CELL).floor() as i32, (end / CELL).ceil() as i32 - 1)
+}
+
+impl<'m> TileCollider<'m> {
+    // The sprite and its flags at a cell, None off the map.
+    pub fn tile(&self, cell_x: i32, cell_y: i32) -> Option<(u8, u8)> {
+        let sprite = self
+            .map
+            .sample(cell_x, cell_y, self.config.layer as usize, self.addressing)?;
+        Some((sprite, self.flags[sprite as usize]))
+    }
+
+    fn tile_has(&self, cell_x: i32, cell_y: i32, flag: u8) -> bool {
+        match self.tile(cell_x, cell_y) {
+            Some((_, flags)) => has_flag(flags, flag),
+            None => false,
+        }
+    }
+
+    // The height of the slope floor at pixel column x, if the cell is a slope.
+    fn slope_floor(&self, x: f32, cell_x: i32, cell_y: i32) -> Option<f32> {
+        let local_x = (x - cell_x as f32 * CELL).clamp(0.0, CELL);
+        let top = cell_y as f32 * CELL;
+        if self.tile_has(cell_x, cell_y, self.config.slope_up) {
+            Some(top + CELL - local_x)
+        } else if self.tile_has(cell_x, cell_y, self.config.slope_down) {
+            Some(top + local_x)
+        } else {
+            None
+        }
+    }
+
+    // Whether the pixel at (x, y) is inside a solid tile or under a slope.
+    pub fn solid_at(&self, x: f32, y: f32) -> bool {
+        let cell_x = (x / CELL).floor() as i32;
+        let cell_y = (y / CELL).floor() as i32;
+        if self.tile_has(cell_x, cell_y, self.config.solid) {
+            return true;
+        }
+        match self.slope_floor(x, cell_x, cell_y) {
+            Some(floor) => y >= floor,
+            None => false,
+        }
+    }
+
+    // Every tile the box overlaps, as (cell x, cell y, sprite).
+    pub fn tiles_in_rect(&self, rect: Aabb) -> Vec<(i32, i32, u8)> {
+        let (x0, x1) = cell_span(rect.x, rect.right());
+        let (y0, y1) = cell_span(rect.y, rect.bottom());
+        let mut tiles = Vec::new();
+        for cell_y in y0..=y1 {
+            for cell_x in x0..=x1 {
+                if let Some((sprite, _)) = self.tile(cell_x, cell_y) {
+                    tiles.push((cell_x, cell_y, sprite));
+                }
+            }
+        }
+        tiles
+    }
+
+    // Whether the box overlaps any tile whose sprite has the flag.
+    pub fn rect_overlaps_flag(&self, rect: Aabb, flag: u8) -> bool {
+        self.tiles_in_rect(rect)
+            .into_iter()
+            .any(|(_, _, sprite)| has_flag(self.flags[sprite as usize], flag))
+    }
+
+    // Moves the box by (dx, dy), stopping at solid tiles and sliding along
+    // them, landing on one way tiles and following slopes.
+    pub fn move_and_slide(&self, rect: Aabb, dx: f32, dy: f32) -> Movement {
+        let mut movement = Movement {
+            rect,
+            contacts: Vec::new(),
+        };
+
+        // Standing on a slope, the row the feet are in is the slope's and
+        // mustn't block walking up onto the ground beside it.
+        let skip_row = if self.slope_under(&rect).is_some() {
+            Some(((rect.bottom() - EPSILON) / CELL).floor() as i32)
+        } else {
+            None
+        };
+
+        self.move_x(&mut movement, dx, skip_row);
+        if skip_row.is_some() {
+            self.step_up(&mut movement);
+        }
+        self.move_y(&mut movement, dy);
+        self.follow_slope(&mut movement, dx, dy);
+        movement
+    }
+
+    fn move_x(&self, movement: &mut Movement, dx: f32, skip_row: Option<i32>) {
+        let rect = &mut movement.rect;
+        if dx == 0.0 {
+            return;
+        }
+        let (row0, row1) = cell_span(rect.y, rect.bottom());
+        let blocks = |cell_x: i32, cell_y: i32| {
+            Some(cell_y) != skip_row && self.tile_has(cell_x, cell_y, self.config.solid)
+        };
+
+        let (start, end, step, edge, normal) = if dx > 0.0 {
+            let (_, last) = cell_span(rect.x, rect.right() + dx);
+            ((rect.right() / CELL).floor() as i32, last, 1, rect.right(), -1.0)
+        } else {
+            let first = ((rect.x + dx) / CELL).floor() as i32;
+            ((rect.x / CELL).ceil() as i32 - 1, first, -1, rect.x, 1.0)
+        };
+
+        let mut cell_x = start;
+        while (step > 0 && cell_x <= end) || (step < 0 && cell_x >= end) {
+            // The near side of the column, it has to be ahead of the box.
+            let near = if step > 0 { cell_x as f32 * CELL } else { (cell_x + 1) as f32 * CELL };
+            let ahead = if step > 0 { near >= edge - EPSILON } else { near <= edge + EPSILON };
+            if ahead {
+                let hits: Vec<i32> = (row0..=row1).filter(|cell_y| blocks(cell_x, *cell_y)).collect();
+                if !hits.is_empty() {
+                    rect.x = if step > 0 { near - rect.w } else { near };
+                    for cell_y in hits {
+                        let (sprite, _) = self.tile(cell_x, cell_y).unwrap_or((0, 0));
+                        movement.contacts.push(Contact {
+                            cell_x,
+                            cell_y,
+                            sprite,
+                            normal: (normal, 0.0),
+                        });
+                    }
+                    return;
+                }
+            }
+            cell_x += step;
+        }
+        rect.x += dx;
+    }
+
+    fn move_y(&self, movement: &mut Movement, dy: f32) {
+        let rect = &mut movement.rect;
+        if dy == 0.0 {
+            return;
+        }
+        let (col0, col1) = cell_span(rect.x, rect.right());
+        let down = dy > 0.0;
+        let blocks = |cell_x: i32, cell_y: i32| {
+            self.tile_has(cell_x, cell_y, self.config.solid)
+                || (down && self.tile_has(cell_x, cell_y, self.config.one_way))
+        };
+
+        let (start, end, step, edge, normal) = if down {
+            let (_, last) = cell_span(rect.y, rect.bottom() + dy);
+            ((rect.bottom() / CELL).floor() as i32, last, 1, rect.bottom(), -1.0)
+        } else {
+            let first = ((rect.y + dy) / CELL).floor() as i32;
+            ((rect.y / CELL).ceil() as i32 - 1, first, -1, rect.y, 1.0)
+        };
+
+        let mut cell_y = start;
+        while (step > 0 && cell_y <= end) || (step < 0 && cell_y >= end) {
+            let near = if step > 0 { cell_y as f32 * CELL } else { (cell_y + 1) as f32 * CELL };
+            let ahead = if step > 0 { near >= edge - EPSILON } else { near <= edge + EPSILON };
+            if ahead {
+                let hits: Vec<i32> = (col0..=col1).filter(|cell_x| blocks(*cell_x, cell_y)).collect();
+                if !hits.is_empty() {
+                    rect.y = if step > 0 { near - rect.h } else { near };
+                    for cell_x in hits {
+                        let (sprite, _) = self.tile(cell_x, cell_y).unwrap_or((0, 0));
+                        movement.contacts.push(Contact {
+                            cell_x,
+                            cell_y,
+                            sprite,
+                            normal: (0.0, normal),
+                        });
+                    }
+                    return;
+                }
+            }
+            cell_y += step;
+        }
+        rect.y += dy;
+    }
+
+    // Coming off the top of a slope onto a solid tile, puts the feet on top of
+    // it once the middle of the box is over it.
+    fn step_up(&self, movement: &mut Movement) {
+        let rect = &mut movement.rect;
+        let cell_x = ((rect.x + rect.w / 2.0) / CELL).floor() as i32;
+        let cell_y = ((rect.bottom() - EPSILON) / CELL).floor() as i32;
+        if self.tile_has(cell_x, cell_y, self.config.solid) {
+            rect.y = cell_y as f32 * CELL - rect.h;
+        }
+    }
+
+    // The slope cell under the bottom middle of the box and its floor height
+    // there, looking in the cell the feet are in and the one below.
+    fn slope_under(&self, rect: &Aabb) -> Option<(i32, i32, f32)> {
+        let foot_x = rect.x + rect.w / 2.0;
+        let cell_x = (foot_x / CELL).floor() as i32;
+        let feet_row = ((rect.bottom() - EPSILON) / CELL).floor() as i32;
+        for cell_y in feet_row..=feet_row + 1 {
+            if let Some(floor) = self.slope_floor(foot_x, cell_x, cell_y) {
+                return Some((cell_x, cell_y, floor));
+            }
+        }
+        None
+    }
+
+    // Puts the box's feet on a slope it sank into, and keeps it on the slope
+    // walking down it rather than stepping off into the air.
+    fn follow_slope(&self, movement: &mut Movement, dx: f32, dy: f32) {
+        let (cell_x, cell_y, floor) = match self.slope_under(&movement.rect) {
+            Some(slope) => slope,
+            None => return,
+        };
+        let bottom = movement.rect.bottom();
+        let snap = if dy >= 0.0 { dx.abs() + EPSILON } else { 0.0 };
+        if bottom < floor - snap {
+            return;
+        }
+
+        movement.rect.y = floor - movement.rect.h;
+        // A floor contact from a solid tile under the slope is replaced by
+        // the slope itself.
+        movement.contacts.retain(|c| c.normal.1 >= 0.0);
+        let (sprite, flags) = self.tile(cell_x, cell_y).unwrap_or((0, 0));
+        let side = if has_flag(flags, self.config.slope_up) { -1.0 } else { 1.0 };
+        movement.contacts.push(Contact {
+            cell_x,
+            cell_y,
+            sprite,
+            normal: (side * std::f32::consts::FRAC_1_SQRT_2, -std::f32::consts::FRAC_1_SQRT_2),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOLID: u8 = 1;
+    const ONE_WAY: u8 = 2;
+    const SLOPE_UP: u8 = 3;
+    const SLOPE_DOWN: u8 = 4;
+
+    fn flags() -> [u8; 256] {
+        let mut flags = [0; 256];
+        flags[SOLID as usize] = 1 << 0;
+        flags[ONE_WAY as usize] = 1 << 1;
+        flags[SLOPE_UP as usize] = 1 << 2;
+        flags[SLOPE_DOWN as usize] = 1 << 3;
+        flags
+    }
+
+    // Builds a map from rows of characters: # solid, - one way, / and \ slopes.
+    fn map(rows: &[&str]) -> TileMap {
+        let mut map = TileMap::new(rows[0].len() as u32, rows.len() as u32, 1);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                let sprite = match c {
+                    '#' => SOLID,
+                    '-' => ONE_WAY,
+                    '/' => SLOPE_UP,
+                    '\\' => SLOPE_DOWN,
+                    _ => 0,
+                };
+                map.set(x as u32, y as u32, 0, sprite);
+            }
+        }
+        map
+    }
+
+    fn collider<'m>(map: &'m TileMap, flags: &'m [u8; 256]) -> TileCollider<'m> {
+        TileCollider {
+            map,
+            flags,
+            config: CollisionConfig::default(),
+            addressing: MapAddressing::Bounded,
+        }
+    }
+
+    #[test]
+    fn solid_points_and_rects() {
+        let map = map(&["....", ".#..", "...."]);
+        let flags = flags();
+        let collider = collider(&map, &flags);
+
+        assert!(collider.solid_at(8.0, 8.0));
+        assert!(collider.solid_at(15.9, 15.9));
+        assert!(!collider.solid_at(16.0, 8.0));
+        assert!(!collider.solid_at(-4.0, -4.0));
+
+        assert!(collider.rect_overlaps_flag(Aabb::new(4.0, 4.0, 8.0, 8.0), 0));
+        assert!(!collider.rect_overlaps_flag(Aabb::new(0.0, 0.0, 8.0, 8.0), 0));
+        assert!(!collider.rect_overlaps_flag(Aabb::new(4.0, 4.0, 8.0, 8.0), 1));
+        assert_eq!(collider.tiles_in_rect(Aabb::new(8.0, 8.0, 8.0, 8.0)), vec![(1, 1, SOLID)]);
+    }
+
+    #[test]
+    fn flags_past_7_are_never_set() {
+        let map = map(&["#"]);
+        // Every sprite is solid and one way, but not a slope.
+        let flags = [0b11; 256];
+        let mut collider = collider(&map, &flags);
+        assert!(!collider.rect_overlaps_flag(Aabb::new(0.0, 0.0, 8.0, 8.0), 8));
+        assert!(!collider.rect_overlaps_flag(Aabb::new(0.0, 0.0, 8.0, 8.0), 255));
+
+        collider.config.solid = 8;
+        assert!(!collider.solid_at(4.0, 4.0));
+
+        assert!(CollisionConfig::default().validate().is_ok());
+        let config = CollisionConfig {
+            slope_down: 8,
+            ..CollisionConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn solid_at_follows_slopes() {
+        let map = map(&["/\\"]);
+        let flags = flags();
+        let collider = collider(&map, &flags);
+
+        assert!(!collider.solid_at(1.0, 1.0));
+        assert!(collider.solid_at(7.0, 2.0));
+        assert!(collider.solid_at(9.0, 7.0));
+        assert!(!collider.solid_at(15.0, 2.0));
+    }
+
+    #[test]
+    fn landing_on_the_floor() {
+        let map = map(&["....", "....", "####"]);
+        let flags = flags();
+        let collider = collider(&map, &flags);
+
+        let movement = collider.move_and_slide(Aabb::new(4.0, 0.0, 8.0, 8.0), 0.0, 20.0);
+        assert_eq!(movement.rect, Aabb::new(4.0, 8.0, 8.0, 8.0));
+        assert!(movement.on_floor());
+        assert_eq!(movement.contacts.len(), 2);
+        assert_eq!(movement.contacts[0].cell_x, 0);
+        assert_eq!(movement.contacts[0].cell_y, 2);
+        assert_eq!(movement.contacts[0].normal, (0.0, -1.0));
+
+        // Resting on the floor, the next move still reports it.
+        let movement = collider.move_and_slide(movement.rect, 0.0, 1.0);
+        assert_eq!(movement.rect.y, 8.0);
+        assert!(movement.on_floor());
+    }
+
+    #[test]
+    fn sliding_along_a_wall() {
+        let map = map(&["...#", "...#", "...#", "...#"]);
+        let flags = flags();
+        let collider = collider(&map, &flags);
+
+        let movement = collider.move_and_slide(Aabb::new(4.0, 0.0, 8.0, 8.0), 20.0, 6.0);
+        assert_eq!(movement.rect, Aabb::new(16.0, 6.0, 8.0, 8.0));
+        assert!(movement.on_wall());
+        assert!(!movement.on_floor());
+        assert!(movement.contacts.iter().all(|c| c.normal == (-1.0, 0.0) && c.cell_x == 3));
+    }
+
+    #[test]
+    fn fast_moves_dont_tunnel() {
+        let map = map(&["..........#....."]);
+        let flags = flags();
+        let collider = collider(&map, &flags);
+
+        let movement = collider.move_and_slide(Aabb::new(0.0, 0.0, 4.0, 4.0), 500.0, 0.0);
+        assert_eq!(movement.rect.x, 76.0);
+        let movement = collider.move_and_slide(Aabb::new(120.0, 0.0, 4.0, 4.0), -500.0, 0.0);
+        assert_eq!(movement.rect.x, 88.0);
+        assert_eq!(movement.contacts[0].normal, (1.0, 0.0));
+    }
+
+    #[test]
+    fn hitting_the_ceiling() {
+        let map = map(&["####", "....", "...."]);
+        let flags = flags();
+        let collider = collider(&map, &flags);
+
+        let movement = collider.move_and_slide(Aabb::new(0.0, 12.0, 8.0, 8.0), 0.0, -10.0);
+        assert_eq!(movement.rect.y, 8.0);
+        assert!(movement.on_ceiling());
+    }
+
+    #[test]
+    fn one_way_platforms() {
+        let map = map(&["....", "....", "----", "....", "...."]);
+        let flags = flags();
+        let collider = collider(&map, &flags);
+
+        // Jumping up through it.
+        let movement = collider.move_and_slide(Aabb::new(0.0, 32.0, 8.0, 8.0), 0.0, -30.0);
+        assert_eq!(movement.rect.y, 2.0);
+        assert!(movement.contacts.is_empty());
+
+        // Landing on it.
+        let movement = collider.move_and_slide(Aabb::new(0.0, 2.0, 8.0, 8.0), 0.0, 10.0);
+        assert_eq!(movement.rect.y, 8.0);
+        assert!(movement.on_floor());
+
+        // Walking through it sideways.
+        let movement = collider.move_and_slide(Aabb::new(0.0, 14.0, 4.0, 4.0), 20.0, 0.0);
+        assert_eq!(movement.rect.x, 20.0);
+    }
+
+    #[test]
+    fn walking_up_and_down_slopes() {
+        let map = map(&["......", "../#\\.", "######"]);
+        let flags = flags();
+        let collider = collider(&map, &flags);
+
+        // Standing on the flat ground left of the slope, feet at y 16.
+        let mut rect = Aabb::new(8.0, 8.0, 4.0, 8.0);
+        let mut seen_slope = false;
+        for _ in 0..16 {
+            let movement = collider.move_and_slide(rect, 1.0, 1.0);
+            rect = movement.rect;
+            assert!(movement.on_floor(), "fell off at x {}", rect.x);
+            if movement.contacts.iter().any(|c| c.normal.0 != 0.0 && c.normal.1 != 0.0) {
+                seen_slope = true;
+            }
+            let foot_x = rect.x + rect.w / 2.0;
+            if foot_x > 16.0 && foot_x < 24.0 {
+                // On the slope the feet follow the line up to the right.
+                assert_eq!(rect.bottom(), 16.0 - (foot_x - 16.0));
+            }
+        }
+        assert!(seen_slope);
+        // Over the solid top and onto the down slope.
+        assert_eq!(rect.x, 24.0);
+        assert_eq!(rect.bottom(), 8.0);
+
+        for _ in 0..8 {
+            let movement = collider.move_and_slide(rect, 1.0, 1.0);
+            rect = movement.rect;
+            assert!(movement.on_floor(), "fell off at x {}", rect.x);
+        }
+        let foot_x = rect.x + rect.w / 2.0;
+        assert_eq!(rect.bottom(), 8.0 + (foot_x - 32.0));
+    }
+
+    #[test]
+    fn wrapped_maps_collide_across_the_edge() {
+        let map = map(&["#..."]);
+        let flags = flags();
+        let mut collider = collider(&map, &flags);
+        assert!(!collider.solid_at(34.0, 2.0));
+
+        collider.addressing = MapAddressing::Wrap;
+        assert!(collider.solid_at(34.0, 2.0));
+        let movement = collider.move_and_slide(Aabb::new(26.0, 0.0, 4.0, 4.0), 10.0, 0.0);
+        assert_eq!(movement.rect.x, 28.0);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::api::{BBMicroApi, BBMicroGame, Button};
+use crate::tilemap::MapDraw;
 
 use rand::rngs::ThreadRng;
 use rand::Rng;
@@ -77,6 +78,16 @@ impl BBMicroGame for Game1 {
         // Draw on layer 1 a bird.
         //api.mset(15, 0, 1, Tiles::Bird as u8);
 
+        // Start the players at the spawn points of a Tiled map, if it has them.
+        if let Some(spawn) = api.map_object("p1") {
+            self.p1x = spawn.x;
+            self.p1y = spawn.y;
+        }
+        if let Some(spawn) = api.map_object("p2") {
+            self.p2x = spawn.x;
+            self.p2y = spawn.y;
+        }
+
         //Play BGM
         api.music("bgm", 0,0); //Uncomment after adding music.mp3
     }
@@ -106,7 +117,11 @@ impl BBMicroGame for Game1 {
         api.camera(self.x - 60.0, self.y - 60.0);
 
         // Draw map layer 0.
-        api.map(0, 0, 0.0, 0.0, 256, 256, 0);
+        api.map(MapDraw {
+            celw: 256,
+            celh: 256,
+            ..MapDraw::default()
+        });
 
         let spr = 1;
 
@@ -118,8 +133,15 @@ impl BBMicroGame for Game1 {
 
         api.spr(self.stop_light.top, self.stop_light.x, self.stop_light.y, 8.0, 8.0, false, false);
         api.spr(self.stop_light.bott, self.stop_light.x, self.stop_light.y + 8.0, 8.0, 8.0, false, false);
+        let label = if self.green_light { "go" } else { "stop" };
+        api.print(label, self.stop_light.x + 10.0, self.stop_light.y + 4.0, true);
 
         // Draw map layer 1.
-        api.map(0, 0, 0.0, 0.0, 256, 256, 1);
+        api.map(MapDraw {
+            celw: 256,
+            celh: 256,
+            layer: 1,
+            ..MapDraw::default()
+        });
     }
 }
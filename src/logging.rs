@@ -0,0 +1,54 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/*
+Runtime diagnostics go through the log crate, under a target per part of the
+engine so they can be filtered separately:
+
+    bbmicro::audio      sfx, music and the mixer
+    bbmicro::assets     loading the project's files
+    bbmicro::graphics   drawing
+    bbmicro::game       the game's own calls, like printh failing
+
+Warnings and errors are shown by default. RUST_LOG changes that, e.g.
+RUST_LOG=bbmicro::audio=debug to see every sound played.
+
+printh is the game's own debug output, as in PICO-8, and isn't filtered.
+*/
+
+pub const AUDIO: &str = "bbmicro::audio";
+pub const ASSETS: &str = "bbmicro::assets";
+pub const GRAPHICS: &str = "bbmicro::graphics";
+pub const GAME: &str = "bbmicro::game";
+
+pub fn init() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+}
+
+// Writes a line to stderr, or appends it to a file.
+pub fn printh(text: &str, file: Option<&Path>) -> io::Result<()> {
+    match file {
+        Some(path) => {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", text)
+        }
+        None => writeln!(io::stderr(), "{}", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printh_appends_lines() {
+        let path = std::env::temp_dir().join(format!("bbmicro_printh_{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        printh("first", Some(&path)).unwrap();
+        printh("second", Some(&path)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 //#![windows_subsystem = "windows"]
 use sdl2::mixer::{AUDIO_S16LSB, DEFAULT_CHANNELS};
 
+use log::warn;
+
 use sdl2::event::Event;
 use sdl2::image::InitFlag;
 use sdl2::keyboard::Keycode;
@@ -11,6 +13,45 @@ use api::{BBMicroGame, InputState};
 mod game1;
 use game1::Game1;
 
+mod assets;
+
+mod audio;
+use audio::SynthBank;
+
+mod autotile;
+
+mod cart;
+use cart::Cart;
+
+mod collision;
+
+mod logging;
+
+mod manifest;
+use manifest::Project;
+
+mod mixer;
+
+mod p8;
+use p8::P8Cart;
+
+mod p8png;
+
+mod pathfind;
+
+mod sdl_audio;
+
+mod sequencer;
+
+mod synth;
+
+mod tiled;
+
+mod tilemap;
+
+mod wav;
+use wav::SoundKind;
+
 struct PlayerInput {
     up: bool,
     down: bool,
@@ -31,7 +72,80 @@ impl PlayerInput {
     }
 }
 
+// Handles `bbmicro pack <project> <out.bbcart>` and `bbmicro unpack <cart> <dir>`.
+// Returns false when the arguments aren't a cart command.
+fn run_cart_command(args: &[String]) -> Result<bool, String> {
+    match args.get(1).map(|s| s.as_str()) {
+        Some("pack") => {
+            if args.len() != 4 {
+                return Err("usage: bbmicro pack <project> <out.bbcart>".to_string());
+            }
+            let cart = Cart::pack(&args[2]).map_err(|e| e.to_string())?;
+            cart.save(&args[3]).map_err(|e| e.to_string())?;
+            Ok(true)
+        }
+        Some("unpack") => {
+            if args.len() != 4 {
+                return Err("usage: bbmicro unpack <cart.bbcart> <dir>".to_string());
+            }
+            let cart = Cart::load(&args[2]).map_err(|e| e.to_string())?;
+            cart.unpack(&args[3]).map_err(|e| e.to_string())?;
+            Ok(true)
+        }
+        Some("wav") => {
+            run_wav_command(&args[2..])?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+// Handles `bbmicro wav <project|cart> <sfx|music> <name> <out.wav>`, see wav.rs.
+// The source can be a project, a .bbcart or a PICO-8 cart.
+fn run_wav_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: bbmicro wav <project|cart> <sfx|music> <name> <out.wav> [--rate <hz>] [--loops <n>]";
+    let mut positional = Vec::new();
+    let mut rate = wav::DEFAULT_RATE;
+    let mut loops = 1;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--rate" => rate = iter.next().and_then(|v| v.parse().ok()).ok_or(usage)?,
+            "--loops" => loops = iter.next().and_then(|v| v.parse().ok()).ok_or(usage)?,
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    let (source, kind, name, out) = match positional[..] {
+        [source, kind, name, out] => (source, kind, name, out),
+        _ => return Err(usage.to_string()),
+    };
+    let kind = SoundKind::parse(kind).ok_or(usage)?;
+
+    let synth = if source.ends_with(".p8") || source.ends_with(".p8.png") {
+        let bytes = std::fs::read(source).map_err(|e| format!("Could not read {}: {}", source, e))?;
+        let cart = P8Cart::from_bytes(source, &bytes).map_err(|e| e.to_string())?;
+        let mut synth = SynthBank::new();
+        synth.import(&cart);
+        synth
+    } else {
+        let project = Project::load(source).map_err(|e| e.to_string())?;
+        api::load_synth(&project).map_err(|e| e.to_string())?
+    };
+    wav::export(&synth, kind, name, rate, loops, out).map_err(|e| e.to_string())
+}
+
 fn main() -> Result<(), String> {
+    logging::init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if run_cart_command(&args)? {
+        return Ok(());
+    }
+
+    // Find the project manifest.
+    let project = Project::locate(&args).map_err(|e| e.to_string())?;
+    let window_config = &project.manifest.window;
+
     // Setup sdl core.
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
@@ -41,7 +155,7 @@ fn main() -> Result<(), String> {
 
     // Setup window.
     let window = video_subsystem
-        .window("BBMicro", 640, 480)
+        .window(&window_config.title, window_config.width, window_config.height)
         .position_centered()
         .build()
         .map_err(|e| e.to_string())?;
@@ -52,9 +166,23 @@ fn main() -> Result<(), String> {
     let format = AUDIO_S16LSB; // signed 16 bit samples, in little-endian byte order
     let channels = DEFAULT_CHANNELS; // Stereo
     let chunk_size = 1_024;
-    sdl2::mixer::open_audio(frequency, format, channels, chunk_size)?;
-    sdl2::mixer::allocate_channels(4);
-    let _mixer_context = sdl2::mixer::init(sdl2::mixer::InitFlag::MP3);
+    // Without an audio device the game runs silently, see audio.rs.
+    let no_audio = args.iter().any(|arg| arg == "--no-audio");
+    let audio_open = if no_audio {
+        Err("disabled with --no-audio".to_string())
+    } else {
+        sdl2::mixer::open_audio(frequency, format, channels, chunk_size)
+    };
+    let _mixer_context = match audio_open {
+        Ok(()) => {
+            sdl2::mixer::allocate_channels(project.manifest.sound.channels);
+            Some(sdl2::mixer::init(sdl2::mixer::InitFlag::MP3))
+        }
+        Err(err) => {
+            warn!(target: logging::AUDIO, "Could not open audio, sound is off: {}", err);
+            None
+        }
+    };
     
     // Setup canvas.
     let mut canvas = window
@@ -73,7 +201,7 @@ fn main() -> Result<(), String> {
     let mut events = sdl_context.event_pump()?;
 
     let mut game = Game1::new();
-    let mut api = api::BBMicroApi::new(&mut canvas, &texture_creator);
+    let mut api = api::BBMicroApi::new(&mut canvas, &texture_creator, project);
 
     // Setup the game.
     game.init(&mut api);
@@ -0,0 +1,380 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::api::load_audio;
+use crate::assets::Assets;
+use crate::cart::{Cart, CART_EXTENSION};
+use crate::autotile::Terrain;
+use crate::collision::CollisionConfig;
+use crate::mixer::Falloff;
+use crate::tilemap::{LayerScroll, MapAddressing, TileFrame, TileMap};
+
+/*
+A project is described by a single manifest, bbmicro.json:
+
+{
+    "asset_root": ".",
+    "sprites": "sprites.png",
+    "flags": "flags.json",
+    "font": { "image": "font.png", "glyphs": "font.json" },
+    "palette": [[0, 0, 0], [29, 43, 83], ...],
+    "audio": "audio.json",
+    "sound": { "channels": 8, "settings": "bbmicro_audio.json",
+               "falloff": { "near": 16, "far": 128, "pan": 64 } },
+    "maps": [],
+    "map": { "width": 256, "height": 256, "layers": 4, "addressing": "bounded", "empty_tile": 0,
+             "scroll": [{ "factor_x": 0.5, "factor_y": 1.0, "wrap_x": true }],
+             "animations": { "16": [{ "sprite": 16, "duration": 250 }, { "sprite": 48, "duration": 250 }] },
+             "terrains": [{ "name": "water", "neighbors": 4, "default": 18, "rules": { "6": 16, "12": 17, ... } }],
+             "collision": { "layer": 0, "solid": 0, "one_way": 1, "slope_up": 2, "slope_down": 3 } },
+    "window": { "title": "BBMicro", "width": 640, "height": 480 }
+}
+
+Asset paths are relative to asset_root, which is itself relative to the
+directory holding the manifest. The manifest path may also point at a .bbcart,
+in which case every asset is read from the cart.
+
+flags is optional and holds a JSON array of up to 256 sprite flag bytes.
+sound.settings is where the player's volume settings are saved, relative to the
+working directory, or null to not save them. sound.falloff is how sfx_at
+sounds fade and pan with distance, see mixer.rs.
+map.empty_tile names a sprite that map skips drawing, as PICO-8 skips sprite 0.
+Without it every cell is drawn.
+pico8 optionally names a .p8 or .p8.png cart whose gfx, flags and map are imported on top.
+*/
+
+pub const MANIFEST_NAME: &str = "bbmicro.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    #[serde(default = "default_asset_root")]
+    pub asset_root: String,
+    pub sprites: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<String>,
+    pub font: FontConfig,
+    #[serde(default = "default_palette")]
+    pub palette: Vec<[u8; 3]>,
+    pub audio: String,
+    #[serde(default)]
+    pub sound: SoundConfig,
+    #[serde(default)]
+    pub maps: Vec<String>,
+    #[serde(default)]
+    pub map: MapConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pico8: Option<String>,
+    #[serde(default)]
+    pub window: WindowConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FontConfig {
+    pub image: String,
+    pub glyphs: String,
+}
+
+// The size of the map the game starts with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MapConfig {
+    #[serde(default = "default_map_size")]
+    pub width: u32,
+    #[serde(default = "default_map_size")]
+    pub height: u32,
+    #[serde(default = "default_map_layers")]
+    pub layers: usize,
+    // "bounded", "wrap" or "clamp".
+    #[serde(default)]
+    pub addressing: MapAddressing,
+    // The sprite map leaves undrawn, by default none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub empty_tile: Option<u8>,
+    // Parallax settings for layers 0, 1, ... in order.
+    #[serde(default)]
+    pub scroll: Vec<LayerScroll>,
+    // Animated tiles, from a base sprite to the frames it cycles through.
+    #[serde(default)]
+    pub animations: BTreeMap<u8, Vec<TileFrame>>,
+    // Autotiling rule sets for mset_terrain.
+    #[serde(default)]
+    pub terrains: Vec<Terrain>,
+    // The layer and sprite flags solid_at and move_and_slide use.
+    #[serde(default)]
+    pub collision: CollisionConfig,
+}
+
+impl Default for MapConfig {
+    fn default() -> MapConfig {
+        MapConfig {
+            width: default_map_size(),
+            height: default_map_size(),
+            layers: default_map_layers(),
+            addressing: MapAddressing::default(),
+            empty_tile: None,
+            scroll: Vec::new(),
+            animations: BTreeMap::new(),
+            terrains: Vec::new(),
+            collision: CollisionConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SoundConfig {
+    // How many mixer channels sfx and pattern music can play on.
+    #[serde(default = "default_sound_channels")]
+    pub channels: i32,
+    #[serde(default = "default_sound_settings")]
+    pub settings: Option<String>,
+    #[serde(default)]
+    pub falloff: Falloff,
+}
+
+impl Default for SoundConfig {
+    fn default() -> SoundConfig {
+        SoundConfig {
+            channels: default_sound_channels(),
+            settings: default_sound_settings(),
+            falloff: Falloff::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WindowConfig {
+    #[serde(default = "default_title")]
+    pub title: String,
+    #[serde(default = "default_width")]
+    pub width: u32,
+    #[serde(default = "default_height")]
+    pub height: u32,
+}
+
+impl Default for WindowConfig {
+    fn default() -> WindowConfig {
+        WindowConfig {
+            title: default_title(),
+            width: default_width(),
+            height: default_height(),
+        }
+    }
+}
+
+fn default_asset_root() -> String {
+    ".".to_string()
+}
+
+fn default_map_size() -> u32 {
+    256
+}
+
+fn default_map_layers() -> usize {
+    4
+}
+
+fn default_sound_channels() -> i32 {
+    4
+}
+
+fn default_sound_settings() -> Option<String> {
+    Some("bbmicro_audio.json".to_string())
+}
+
+fn default_title() -> String {
+    "BBMicro".to_string()
+}
+
+fn default_width() -> u32 {
+    640
+}
+
+fn default_height() -> u32 {
+    480
+}
+
+// The PICO-8 palette.
+pub fn default_palette() -> Vec<[u8; 3]> {
+    vec![
+        [0, 0, 0],
+        [29, 43, 83],
+        [126, 37, 83],
+        [0, 135, 81],
+        [117, 82, 54],
+        [95, 87, 79],
+        [194, 195, 199],
+        [255, 241, 232],
+        [255, 0, 77],
+        [255, 163, 0],
+        [255, 236, 39],
+        [0, 228, 54],
+        [41, 173, 255],
+        [131, 118, 156],
+        [255, 119, 168],
+        [255, 204, 170],
+    ]
+}
+
+impl Manifest {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.palette.is_empty() {
+            return Err("palette must not be empty".into());
+        }
+        if self.map.layers == 0 || self.map.layers > u8::MAX as usize {
+            return Err("map.layers must be between 1 and 255".into());
+        }
+        TileMap::check_size(self.map.width, self.map.height, self.map.layers).map_err(|e| format!("map: {}", e))?;
+        for terrain in &self.map.terrains {
+            terrain.validate()?;
+        }
+        self.map.collision.validate()?;
+        // Channel masks are 32 bits.
+        if self.sound.channels < 1 || self.sound.channels > 32 {
+            return Err("sound.channels must be between 1 and 32".into());
+        }
+        self.sound.falloff.validate()?;
+        Ok(())
+    }
+
+    // Every asset file the project needs, including the sounds named in the
+    // audio config.
+    pub fn files(&self, assets: &Assets) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut files = vec![
+            self.sprites.clone(),
+            self.font.image.clone(),
+            self.font.glyphs.clone(),
+            self.audio.clone(),
+        ];
+        files.extend(self.flags.iter().cloned());
+        files.extend(self.pico8.iter().cloned());
+        files.extend(self.maps.iter().cloned());
+
+        let audio_config = load_audio(assets, &self.audio)?;
+        for entry in audio_config.music.iter().chain(audio_config.sfx.iter()) {
+            files.push(entry.path.clone());
+        }
+
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+}
+
+pub struct Project {
+    pub manifest: Manifest,
+    pub assets: Assets,
+}
+
+impl Project {
+    // Loads a project from a manifest file, a directory containing
+    // bbmicro.json, or a .bbcart.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Project, Box<dyn Error>> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|ext| ext == CART_EXTENSION) {
+            return Project::from_cart(Cart::load(path)?);
+        }
+
+        let manifest_path = if path.is_dir() {
+            path.join(MANIFEST_NAME)
+        } else {
+            path.to_path_buf()
+        };
+
+        let file = File::open(&manifest_path)
+            .map_err(|e| format!("Could not open {}: {}", manifest_path.display(), e))?;
+        let reader = BufReader::new(file);
+        let manifest: Manifest = serde_json::from_reader(reader)
+            .map_err(|e| format!("Could not parse {}: {}", manifest_path.display(), e))?;
+
+        manifest
+            .validate()
+            .map_err(|e| format!("{}: {}", manifest_path.display(), e))?;
+
+        let dir = match manifest_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let assets = Assets::Disk(dir.join(&manifest.asset_root));
+
+        Ok(Project { manifest, assets })
+    }
+
+    pub fn from_cart(cart: Cart) -> Result<Project, Box<dyn Error>> {
+        let manifest = cart.manifest()?;
+        manifest.validate()?;
+
+        Ok(Project {
+            manifest,
+            assets: Assets::Cart(cart),
+        })
+    }
+
+    // Loads the project that build.rs baked into the executable.
+    #[cfg(all(feature = "embed-assets", not(debug_assertions)))]
+    pub fn embedded() -> Result<Project, Box<dyn Error>> {
+        let manifest: Manifest = serde_json::from_slice(crate::assets::embedded::MANIFEST)
+            .map_err(|e| format!("Could not parse embedded {}: {}", MANIFEST_NAME, e))?;
+        manifest.validate()?;
+
+        Ok(Project {
+            manifest,
+            assets: Assets::Embedded,
+        })
+    }
+
+    // Finds the project to run. An explicit `--project <path>` wins, then the
+    // embedded assets if this build has them, then a manifest next to the
+    // executable, then one in the working directory.
+    pub fn locate(args: &[String]) -> Result<Project, Box<dyn Error>> {
+        if let Some(path) = project_arg(args) {
+            return Project::load(path);
+        }
+
+        #[cfg(all(feature = "embed-assets", not(debug_assertions)))]
+        {
+            Project::embedded()
+        }
+        #[cfg(not(all(feature = "embed-assets", not(debug_assertions))))]
+        {
+            Project::locate_on_disk()
+        }
+    }
+
+    #[cfg(not(all(feature = "embed-assets", not(debug_assertions))))]
+    fn locate_on_disk() -> Result<Project, Box<dyn Error>> {
+        if let Ok(exe) = std::env::current_exe() {
+            if let Some(exe_dir) = exe.parent() {
+                if exe_dir.join(MANIFEST_NAME).is_file() {
+                    return Project::load(exe_dir);
+                }
+            }
+        }
+
+        if Path::new(MANIFEST_NAME).is_file() {
+            return Project::load(MANIFEST_NAME);
+        }
+
+        Err(format!(
+            "Could not find {} next to the executable or in the working directory, use --project <path>",
+            MANIFEST_NAME
+        )
+        .into())
+    }
+}
+
+fn project_arg(args: &[String]) -> Option<&str> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--project" {
+            return iter.next().map(|s| s.as_str());
+        }
+        if let Some(path) = arg.strip_prefix("--project=") {
+            return Some(path);
+        }
+    }
+    None
+}
@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/*
+Volume settings for the mixer. Every sound goes through the master bus and
+either the music or the sfx bus, and sfx can have a volume of their own:
+
+    music   master * music
+    sfx     master * sfx * the volume sfx_with was given
+
+Volumes are 0.0..1.0 and mute silences everything without changing them. The
+settings are the player's, so they're saved to a file outside the project,
+named by sound.settings in the manifest.
+
+Sounds played at a position in the world are quieter the further they are from
+the middle of the screen, and pan with how far left or right of it they are,
+as set by sound.falloff in the manifest:
+
+    near   within this many pixels sounds play at full volume
+    far    from here on they're silent, the volume falls linearly in between
+    pan    this many pixels to the side they play on one side only
+*/
+
+// SDL_mixer's full volume.
+const MIX_MAX_VOLUME: f32 = 128.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioBus {
+    Master,
+    Music,
+    Sfx,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub master: f32,
+    pub music: f32,
+    pub sfx: f32,
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            master: 1.0,
+            music: 1.0,
+            sfx: 1.0,
+            muted: false,
+        }
+    }
+}
+
+impl AudioSettings {
+    // Reads the settings saved at `path`, the defaults if there are none yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<AudioSettings, Box<dyn Error>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(AudioSettings::default());
+        }
+        let bytes = fs::read(path)?;
+        let settings: AudioSettings = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+        Ok(settings.clamped())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn clamped(self) -> AudioSettings {
+        AudioSettings {
+            master: self.master.clamp(0.0, 1.0),
+            music: self.music.clamp(0.0, 1.0),
+            sfx: self.sfx.clamp(0.0, 1.0),
+            muted: self.muted,
+        }
+    }
+
+    pub fn volume(&self, bus: AudioBus) -> f32 {
+        match bus {
+            AudioBus::Master => self.master,
+            AudioBus::Music => self.music,
+            AudioBus::Sfx => self.sfx,
+        }
+    }
+
+    pub fn set_volume(&mut self, bus: AudioBus, volume: f32) {
+        let volume = volume.clamp(0.0, 1.0);
+        match bus {
+            AudioBus::Master => self.master = volume,
+            AudioBus::Music => self.music = volume,
+            AudioBus::Sfx => self.sfx = volume,
+        }
+    }
+
+    // The SDL_mixer volume (0..128) of a sound on the music or sfx bus
+    // played at `volume`.
+    pub fn mix_volume(&self, bus: AudioBus, volume: f32) -> i32 {
+        if self.muted {
+            return 0;
+        }
+        let volume = self.master * self.volume(bus) * volume.clamp(0.0, 1.0);
+        (volume * MIX_MAX_VOLUME).round() as i32
+    }
+}
+
+// The left and right volumes (0..255) for a pan from -1.0 (left) to 1.0
+// (right). The center plays at full volume on both sides.
+pub fn panning(pan: f32) -> (u8, u8) {
+    let pan = pan.clamp(-1.0, 1.0);
+    let left = (1.0 - pan).min(1.0) * 255.0;
+    let right = (1.0 + pan).min(1.0) * 255.0;
+    (left.round() as u8, right.round() as u8)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Falloff {
+    pub near: f32,
+    pub far: f32,
+    pub pan: f32,
+}
+
+impl Default for Falloff {
+    fn default() -> Falloff {
+        Falloff {
+            near: 16.0,
+            far: 128.0,
+            pan: 64.0,
+        }
+    }
+}
+
+impl Falloff {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.near < 0.0 || self.far <= self.near {
+            return Err(format!(
+                "sound.falloff needs 0 <= near < far, got near {} and far {}",
+                self.near, self.far
+            ));
+        }
+        if self.pan <= 0.0 {
+            return Err(format!("sound.falloff.pan must be above 0, got {}", self.pan));
+        }
+        Ok(())
+    }
+
+    // The volume (0.0..1.0) and pan of a sound `dx`, `dy` pixels from the
+    // listener.
+    pub fn attenuate(&self, dx: f32, dy: f32) -> (f32, f32) {
+        let distance = (dx * dx + dy * dy).sqrt();
+        let volume = 1.0 - (distance - self.near) / (self.far - self.near);
+        (volume.clamp(0.0, 1.0), (dx / self.pan).clamp(-1.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buses_multiply() {
+        let mut settings = AudioSettings::default();
+        assert_eq!(settings.mix_volume(AudioBus::Sfx, 1.0), 128);
+
+        settings.set_volume(AudioBus::Master, 0.5);
+        settings.set_volume(AudioBus::Sfx, 0.5);
+        assert_eq!(settings.mix_volume(AudioBus::Sfx, 1.0), 32);
+        assert_eq!(settings.mix_volume(AudioBus::Sfx, 0.5), 16);
+        assert_eq!(settings.mix_volume(AudioBus::Music, 1.0), 64);
+
+        settings.set_volume(AudioBus::Music, 3.0);
+        assert_eq!(settings.volume(AudioBus::Music), 1.0);
+
+        settings.muted = true;
+        assert_eq!(settings.mix_volume(AudioBus::Music, 1.0), 0);
+        assert_eq!(settings.volume(AudioBus::Master), 0.5);
+    }
+
+    #[test]
+    fn pans() {
+        assert_eq!(panning(0.0), (255, 255));
+        assert_eq!(panning(-1.0), (255, 0));
+        assert_eq!(panning(1.0), (0, 255));
+        assert_eq!(panning(0.5), (128, 255));
+        assert_eq!(panning(-7.0), (255, 0));
+    }
+
+    #[test]
+    fn falls_off_with_distance() {
+        let falloff = Falloff {
+            near: 10.0,
+            far: 110.0,
+            pan: 50.0,
+        };
+        assert_eq!(falloff.attenuate(0.0, 0.0), (1.0, 0.0));
+        assert_eq!(falloff.attenuate(0.0, -10.0), (1.0, 0.0));
+        assert_eq!(falloff.attenuate(0.0, 60.0), (0.5, 0.0));
+        assert_eq!(falloff.attenuate(-30.0, 40.0), (0.6, -0.6));
+        assert_eq!(falloff.attenuate(200.0, 0.0), (0.0, 1.0));
+
+        assert!(falloff.validate().is_ok());
+        assert!(Falloff { far: 10.0, ..falloff }.validate().is_err());
+        assert!(Falloff { pan: 0.0, ..falloff }.validate().is_err());
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let path = std::env::temp_dir().join(format!("bbmicro_audio_settings_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(AudioSettings::load(&path).unwrap(), AudioSettings::default());
+
+        let settings = AudioSettings {
+            master: 0.25,
+            music: 0.5,
+            sfx: 0.75,
+            muted: true,
+        };
+        settings.save(&path).unwrap();
+        assert_eq!(AudioSettings::load(&path).unwrap(), settings);
+
+        // Missing fields keep their defaults and volumes are kept in range.
+        fs::write(&path, r#"{ "music": 2.0 }"#).unwrap();
+        let loaded = AudioSettings::load(&path).unwrap();
+        assert_eq!(loaded.music, 1.0);
+        assert_eq!(loaded.master, 1.0);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,463 @@
+use std::error::Error;
+
+use crate::p8png::decode_p8_png;
+
+/*
+PICO-8 cartridge data, kept in the layout of the PICO-8 ROM so text carts and
+other sources decode the same way.
+
+0x0000 gfx     128x128 pixels, 4 bits each, low nibble is the left pixel
+0x1000         also map rows 32..63 (the shared half of the spritesheet)
+0x2000 map     128x32 tiles, one byte each
+0x3000 gff     256 sprite flag bytes
+0x3100 music   64 patterns x 4 bytes
+0x3200 sfx     64 effects x 68 bytes (32 notes x 2 bytes, then editor mode,
+               speed, loop start, loop end)
+0x4300 code
+*/
+
+pub const ROM_SIZE: usize = 0x4300;
+
+pub const GFX_ADDR: usize = 0x0000;
+pub const MAP_ADDR: usize = 0x2000;
+pub const SHARED_MAP_ADDR: usize = 0x1000;
+pub const GFF_ADDR: usize = 0x3000;
+pub const MUSIC_ADDR: usize = 0x3100;
+pub const SFX_ADDR: usize = 0x3200;
+
+pub const MAP_WIDTH: u32 = 128;
+pub const MAP_HEIGHT: u32 = 64;
+
+pub const SFX_COUNT: usize = 64;
+pub const MUSIC_COUNT: usize = 64;
+pub const SFX_NOTES: usize = 32;
+
+const SFX_SIZE: usize = 68;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct P8Note {
+    // 0..63, C-0 upwards.
+    pub pitch: u8,
+    // 0..7, the built in waveforms.
+    pub waveform: u8,
+    // 0..7, 0 is silent.
+    pub volume: u8,
+    // 0..7: none, slide, vibrato, drop, fade in, fade out, arp fast, arp slow.
+    pub effect: u8,
+    // When set, waveform is the index of an sfx (0..7) used as the instrument.
+    pub custom: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct P8Sfx {
+    pub notes: [P8Note; SFX_NOTES],
+    pub editor_mode: u8,
+    // Duration of each note in 1/128ths of a second.
+    pub speed: u8,
+    pub loop_start: u8,
+    pub loop_end: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct P8Music {
+    // The sfx played on each of the 4 channels, None if the channel is off.
+    pub channels: [Option<u8>; 4],
+    pub loop_start: bool,
+    pub loop_end: bool,
+    pub stop: bool,
+}
+
+impl P8Sfx {
+    // Parses a line in the format of a cart's __sfx__ section.
+    pub fn from_line(line: &str) -> Result<P8Sfx, Box<dyn Error>> {
+        let mut cart = P8Cart::new();
+        cart.parse_sfx_line(0, line.trim())?;
+        Ok(cart.sfx(0))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.iter().all(|note| note.volume == 0)
+    }
+}
+
+impl P8Music {
+    // Parses a line in the format of a cart's __music__ section.
+    pub fn from_line(line: &str) -> Result<P8Music, Box<dyn Error>> {
+        let mut cart = P8Cart::new();
+        cart.parse_music_line(0, line.trim())?;
+        Ok(cart.music(0))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.iter().all(|channel| channel.is_none())
+    }
+}
+
+pub struct P8Cart {
+    pub rom: Vec<u8>,
+    pub code: String,
+}
+
+impl P8Cart {
+    pub fn new() -> P8Cart {
+        let mut rom = vec![0; ROM_SIZE];
+        // Music patterns start with every channel off, as in PICO-8.
+        for pattern in rom[MUSIC_ADDR..SFX_ADDR].chunks_mut(4) {
+            pattern.copy_from_slice(&[0x41, 0x42, 0x43, 0x44]);
+        }
+        P8Cart {
+            rom,
+            code: String::new(),
+        }
+    }
+
+    // Parses a .p8 text cart, or a .p8.png image cart.
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<P8Cart, Box<dyn Error>> {
+        if name.ends_with(".png") {
+            decode_p8_png(bytes)
+        } else {
+            P8Cart::from_p8_text(&String::from_utf8_lossy(bytes))
+        }
+    }
+
+    // Parses a .p8 text cartridge.
+    pub fn from_p8_text(text: &str) -> Result<P8Cart, Box<dyn Error>> {
+        let mut cart = P8Cart::new();
+        let mut lines = text.lines();
+
+        match lines.next() {
+            Some(header) if header.starts_with("pico-8 cartridge") => {}
+            _ => return Err("Missing the pico-8 cartridge header".into()),
+        }
+
+        let mut section = "";
+        let mut row = 0;
+        for line in lines {
+            let trimmed = line.trim_end();
+            if trimmed.starts_with("__") && trimmed.ends_with("__") && trimmed.len() > 4 {
+                section = &trimmed[2..trimmed.len() - 2];
+                row = 0;
+                continue;
+            }
+
+            match section {
+                "lua" => {
+                    cart.code.push_str(line);
+                    cart.code.push('\n');
+                }
+                "gfx" => cart.parse_gfx_line(row, trimmed)?,
+                // 2 rows of flags and 32 of map, more would spill into what
+                // follows them.
+                "gff" if row < 2 => cart.parse_bytes_line(GFF_ADDR + row * 128, 128, trimmed)?,
+                "map" if row < 32 => cart.parse_bytes_line(MAP_ADDR + row * 128, 128, trimmed)?,
+                "sfx" => cart.parse_sfx_line(row, trimmed)?,
+                "music" => cart.parse_music_line(row, trimmed)?,
+                _ => {}
+            }
+            if section != "lua" && !trimmed.is_empty() {
+                row += 1;
+            }
+        }
+
+        Ok(cart)
+    }
+
+    fn parse_gfx_line(&mut self, row: usize, line: &str) -> Result<(), Box<dyn Error>> {
+        if row >= 128 || line.is_empty() {
+            return Ok(());
+        }
+        for (x, c) in line.chars().take(128).enumerate() {
+            let col = hex_digit(c)?;
+            self.set_gfx_pixel(x as u32, row as u32, col);
+        }
+        Ok(())
+    }
+
+    fn parse_bytes_line(&mut self, addr: usize, len: usize, line: &str) -> Result<(), Box<dyn Error>> {
+        let bytes = hex_bytes(line)?;
+        for (i, byte) in bytes.into_iter().take(len).enumerate() {
+            if let Some(slot) = self.rom.get_mut(addr + i) {
+                *slot = byte;
+            }
+        }
+        Ok(())
+    }
+
+    // An sfx line is 4 header bytes (editor mode, speed, loop start, loop end)
+    // followed by 32 notes of 5 hex digits: pitch (2), waveform, volume, effect.
+    fn parse_sfx_line(&mut self, row: usize, line: &str) -> Result<(), Box<dyn Error>> {
+        if row >= SFX_COUNT || line.is_empty() {
+            return Ok(());
+        }
+        // Sliced by byte below, so only ASCII will do.
+        if !line.is_ascii() {
+            return Err(format!("sfx {} has characters that aren't hex digits", row).into());
+        }
+        if line.len() < 8 + SFX_NOTES * 5 {
+            return Err(format!("sfx {} is too short", row).into());
+        }
+
+        let addr = SFX_ADDR + row * SFX_SIZE;
+        let header = hex_bytes(&line[0..8])?;
+        self.rom[addr + 64..addr + 68].copy_from_slice(&header);
+
+        for n in 0..SFX_NOTES {
+            let note = &line[8 + n * 5..8 + n * 5 + 5];
+            let pitch = u16::from(hex_bytes(&note[0..2])?[0]);
+            let waveform = u16::from(hex_digit(note.as_bytes()[2] as char)?);
+            let volume = u16::from(hex_digit(note.as_bytes()[3] as char)?);
+            let effect = u16::from(hex_digit(note.as_bytes()[4] as char)?);
+
+            let value = (pitch & 0x3f)
+                | (waveform & 0x7) << 6
+                | (volume & 0x7) << 9
+                | (effect & 0x7) << 12
+                | (waveform >> 3) << 15;
+            self.rom[addr + n * 2..addr + n * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    // A music line is a flags byte then the 4 channel bytes, e.g. "01 41424344".
+    fn parse_music_line(&mut self, row: usize, line: &str) -> Result<(), Box<dyn Error>> {
+        if row >= MUSIC_COUNT || line.is_empty() {
+            return Ok(());
+        }
+        let mut parts = line.split_whitespace();
+        let flags = hex_bytes(parts.next().unwrap_or(""))?;
+        let channels = hex_bytes(parts.next().unwrap_or(""))?;
+        if flags.len() != 1 || channels.len() != 4 {
+            return Err(format!("music {} is malformed", row).into());
+        }
+
+        let addr = MUSIC_ADDR + row * 4;
+        for (i, channel) in channels.iter().enumerate() {
+            self.rom[addr + i] = (channel & 0x7f) | ((flags[0] >> i) & 1) << 7;
+        }
+        Ok(())
+    }
+
+    pub fn gfx_pixel(&self, x: u32, y: u32) -> u8 {
+        let byte = self.rom[GFX_ADDR + (y * 64 + x / 2) as usize];
+        if x & 1 == 0 {
+            byte & 0x0f
+        } else {
+            byte >> 4
+        }
+    }
+
+    pub fn set_gfx_pixel(&mut self, x: u32, y: u32, col: u8) {
+        let byte = &mut self.rom[GFX_ADDR + (y * 64 + x / 2) as usize];
+        if x & 1 == 0 {
+            *byte = (*byte & 0xf0) | (col & 0x0f);
+        } else {
+            *byte = (*byte & 0x0f) | (col << 4);
+        }
+    }
+
+    pub fn sprite_flags(&self) -> &[u8] {
+        &self.rom[GFF_ADDR..GFF_ADDR + 256]
+    }
+
+    // Map rows 32..63 live in the second half of the spritesheet.
+    pub fn map_cell(&self, x: u32, y: u32) -> u8 {
+        if y < 32 {
+            self.rom[MAP_ADDR + (y * MAP_WIDTH + x) as usize]
+        } else {
+            self.rom[SHARED_MAP_ADDR + ((y - 32) * MAP_WIDTH + x) as usize]
+        }
+    }
+
+    pub fn sfx(&self, n: usize) -> P8Sfx {
+        let addr = SFX_ADDR + n * SFX_SIZE;
+        let data = &self.rom[addr..addr + SFX_SIZE];
+
+        let mut notes = [P8Note {
+            pitch: 0,
+            waveform: 0,
+            volume: 0,
+            effect: 0,
+            custom: false,
+        }; SFX_NOTES];
+        for (i, note) in notes.iter_mut().enumerate() {
+            let value = u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+            *note = P8Note {
+                pitch: (value & 0x3f) as u8,
+                waveform: ((value >> 6) & 0x7) as u8,
+                volume: ((value >> 9) & 0x7) as u8,
+                effect: ((value >> 12) & 0x7) as u8,
+                custom: value >> 15 != 0,
+            };
+        }
+
+        P8Sfx {
+            notes,
+            editor_mode: data[64],
+            speed: data[65],
+            loop_start: data[66],
+            loop_end: data[67],
+        }
+    }
+
+    pub fn music(&self, n: usize) -> P8Music {
+        let addr = MUSIC_ADDR + n * 4;
+        let data = &self.rom[addr..addr + 4];
+
+        let mut channels = [None; 4];
+        for (i, channel) in channels.iter_mut().enumerate() {
+            // Bit 6 marks a disabled channel.
+            if data[i] & 0x40 == 0 {
+                *channel = Some(data[i] & 0x3f);
+            }
+        }
+
+        P8Music {
+            channels,
+            loop_start: data[0] & 0x80 != 0,
+            loop_end: data[1] & 0x80 != 0,
+            stop: data[2] & 0x80 != 0,
+        }
+    }
+}
+
+fn hex_digit(c: char) -> Result<u8, Box<dyn Error>> {
+    c.to_digit(16)
+        .map(|d| d as u8)
+        .ok_or_else(|| format!("Invalid hex digit '{}'", c).into())
+}
+
+fn hex_bytes(text: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let digits: Vec<char> = text.chars().collect();
+    digits
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Ok(hex_digit(*hi)? << 4 | hex_digit(*lo)?),
+            _ => Err("Odd number of hex digits".into()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_text_carts() {
+        let sfx = format!("00100408{}", "1ad43".repeat(SFX_NOTES));
+        let text = format!(
+            "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\nprint(\"hi\")\n\n__gfx__\n01\n\n0f7\n__gff__\n0102\n__map__\n0a0b\n__sfx__\n{}\n__music__\n03 01024344\n",
+            sfx
+        );
+        let cart = P8Cart::from_p8_text(&text).unwrap();
+
+        assert_eq!(cart.code, "print(\"hi\")\n\n");
+        assert_eq!((cart.gfx_pixel(0, 0), cart.gfx_pixel(1, 0)), (0, 1));
+        // Blank lines don't count as rows.
+        assert_eq!((cart.gfx_pixel(0, 1), cart.gfx_pixel(1, 1), cart.gfx_pixel(2, 1)), (0, 15, 7));
+        assert_eq!(&cart.sprite_flags()[..3], &[1, 2, 0]);
+        assert_eq!((cart.map_cell(0, 0), cart.map_cell(1, 0)), (10, 11));
+
+        let sfx = cart.sfx(0);
+        assert_eq!((sfx.editor_mode, sfx.speed, sfx.loop_start, sfx.loop_end), (0, 16, 4, 8));
+        assert_eq!(
+            sfx.notes[0],
+            P8Note {
+                pitch: 0x1a,
+                waveform: 5,
+                volume: 4,
+                effect: 3,
+                custom: true,
+            }
+        );
+
+        let music = cart.music(0);
+        assert_eq!(music.channels, [Some(1), Some(2), None, None]);
+        assert!(music.loop_start && music.loop_end && !music.stop);
+        assert!(cart.music(1).is_empty());
+    }
+
+    #[test]
+    fn needs_a_header() {
+        let err = P8Cart::from_p8_text("__gfx__\n01\n").err().unwrap();
+        assert_eq!(err.to_string(), "Missing the pico-8 cartridge header");
+        assert!(P8Cart::from_p8_text("").is_err());
+    }
+
+    #[test]
+    fn gfx_low_nibble_is_the_left_pixel() {
+        let mut cart = P8Cart::new();
+        cart.rom[GFX_ADDR + 64 + 1] = 0x9c;
+        assert_eq!(cart.gfx_pixel(2, 1), 0xc);
+        assert_eq!(cart.gfx_pixel(3, 1), 0x9);
+
+        cart.set_gfx_pixel(2, 1, 0x3);
+        assert_eq!(cart.rom[GFX_ADDR + 65], 0x93);
+    }
+
+    #[test]
+    fn lower_map_rows_share_the_gfx() {
+        let mut cart = P8Cart::new();
+        // Map row 32 is gfx rows 64 and 65, two pixels to a byte.
+        cart.set_gfx_pixel(0, 64, 0x5);
+        cart.set_gfx_pixel(1, 64, 0x2);
+        assert_eq!(cart.map_cell(0, 32), 0x25);
+        cart.rom[SHARED_MAP_ADDR + 129] = 7;
+        assert_eq!(cart.map_cell(1, 33), 7);
+    }
+
+    #[test]
+    fn map_and_flag_rows_stay_in_their_areas() {
+        let mut text = "pico-8 cartridge\n__map__\n".to_string();
+        for _ in 0..40 {
+            text.push_str(&"ff".repeat(128));
+            text.push('\n');
+        }
+        text.push_str("__gff__\n");
+        for _ in 0..3 {
+            text.push_str(&"11".repeat(128));
+            text.push('\n');
+        }
+        let cart = P8Cart::from_p8_text(&text).unwrap();
+        assert_eq!(cart.map_cell(127, 31), 0xff);
+        assert_eq!(cart.sprite_flags()[255], 0x11);
+        assert!(cart.music(0).is_empty());
+        assert_eq!(cart.rom[SFX_ADDR], 0);
+    }
+
+    #[test]
+    fn sfx_notes_round_trip() {
+        let mut line = "01200010".to_string();
+        let notes = ["18240", "2bf57", "00000", "3f741"];
+        for n in 0..SFX_NOTES {
+            line.push_str(notes[n % notes.len()]);
+        }
+        let sfx = P8Sfx::from_line(&line).unwrap();
+        assert_eq!(sfx.notes[0], P8Note { pitch: 0x18, waveform: 2, volume: 4, effect: 0, custom: false });
+        assert_eq!(sfx.notes[1], P8Note { pitch: 0x2b, waveform: 7, volume: 5, effect: 7, custom: true });
+        assert_eq!(sfx.notes[3], P8Note { pitch: 0x3f, waveform: 7, volume: 4, effect: 1, custom: false });
+        assert_eq!((sfx.editor_mode, sfx.speed, sfx.loop_start, sfx.loop_end), (1, 32, 0, 16));
+
+        // Packing the notes again gives back the same line.
+        let mut cart = P8Cart::new();
+        cart.parse_sfx_line(5, &line).unwrap();
+        assert_eq!(cart.sfx(5), sfx);
+        let unpacked: String = sfx
+            .notes
+            .iter()
+            .map(|note| {
+                let waveform = note.waveform + if note.custom { 8 } else { 0 };
+                format!("{:02x}{:x}{:x}{:x}", note.pitch, waveform, note.volume, note.effect)
+            })
+            .collect();
+        assert_eq!(unpacked, line[8..]);
+    }
+
+    #[test]
+    fn bad_sfx_lines_are_errors() {
+        assert!(P8Sfx::from_line("0010").is_err());
+        let mut line = format!("00100000{}", "0".repeat(SFX_NOTES * 5));
+        line.replace_range(10..11, "\u{fffd}");
+        assert!(P8Sfx::from_line(&line).is_err());
+        assert!(P8Sfx::from_line(&format!("é{}", "0".repeat(200))).is_err());
+    }
+}
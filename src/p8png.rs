@@ -0,0 +1,389 @@
+use std::error::Error;
+
+use crate::p8::{P8Cart, ROM_SIZE};
+
+/*
+A .p8.png hides the 32K PICO-8 ROM in the low two bits of each channel of a
+160x205 image. Each pixel holds one byte: ARGB, two bits each, A highest.
+
+The code section at 0x4300 is either plain text terminated by a zero, the old
+":c:\0" compressed format, or the newer "\0pxa" format. Code is decoded to text
+with one char per byte; PICO-8's glyphs above 0x7f are kept as raw bytes.
+*/
+
+const CART_WIDTH: u32 = 160;
+const CART_HEIGHT: u32 = 205;
+const CODE_ADDR: usize = ROM_SIZE;
+const CODE_END: usize = 0x8000;
+
+const OLD_HEADER: &[u8; 4] = b":c:\0";
+const PXA_HEADER: &[u8; 4] = b"\0pxa";
+const OLD_CODE_TABLE: &[u8] = b"\n 0123456789abcdefghijklmnopqrstuvwxyz!#%(){}[]<>+=/*:;.,~_";
+
+pub fn decode_p8_png(bytes: &[u8]) -> Result<P8Cart, Box<dyn Error>> {
+    let rom = extract_rom(bytes)?;
+
+    let mut cart = P8Cart::new();
+    cart.rom.copy_from_slice(&rom[..ROM_SIZE]);
+    cart.code = decode_code(&rom[CODE_ADDR..CODE_END])?;
+    Ok(cart)
+}
+
+// Reads the 0x8000 byte ROM out of the image.
+pub fn extract_rom(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+
+    if info.width != CART_WIDTH || info.height != CART_HEIGHT {
+        return Err(format!(
+            "Expected a {}x{} image, got {}x{}",
+            CART_WIDTH, CART_HEIGHT, info.width, info.height
+        )
+        .into());
+    }
+    if info.color_type != png::ColorType::Rgba {
+        return Err(format!("Expected an RGBA image, got {:?}", info.color_type).into());
+    }
+
+    let mut rom = Vec::with_capacity(CODE_END);
+    for y in 0..info.height as usize {
+        let line = &pixels[y * info.line_size..(y + 1) * info.line_size];
+        for pixel in line.chunks(4) {
+            if rom.len() == CODE_END {
+                break;
+            }
+            let (r, g, b, a) = (pixel[0], pixel[1], pixel[2], pixel[3]);
+            rom.push((a & 3) << 6 | (r & 3) << 4 | (g & 3) << 2 | (b & 3));
+        }
+    }
+
+    Ok(rom)
+}
+
+pub fn decode_code(data: &[u8]) -> Result<String, Box<dyn Error>> {
+    let bytes = if data.starts_with(OLD_HEADER) {
+        decompress_old(data)?
+    } else if data.starts_with(PXA_HEADER) {
+        decompress_pxa(data)?
+    } else {
+        data.iter().take_while(|b| **b != 0).cloned().collect()
+    };
+
+    Ok(bytes.into_iter().map(|b| b as char).collect())
+}
+
+fn read_len(data: &[u8]) -> Result<usize, Box<dyn Error>> {
+    if data.len() < 8 {
+        return Err("Compressed code header is truncated".into());
+    }
+    Ok(u16::from_be_bytes([data[4], data[5]]) as usize)
+}
+
+fn copy_back(out: &mut Vec<u8>, offset: usize, count: usize) -> Result<(), Box<dyn Error>> {
+    if offset == 0 || offset > out.len() {
+        return Err(format!("Back reference {} is out of range", offset).into());
+    }
+    for _ in 0..count {
+        out.push(out[out.len() - offset]);
+    }
+    Ok(())
+}
+
+// Header, 2 byte big-endian length, 2 zero bytes. Then each byte is:
+// 0x00 followed by a literal byte, 0x01..0x3b a char from OLD_CODE_TABLE,
+// or the start of a 2 byte back reference.
+fn decompress_old(data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let len = read_len(data)?;
+    let mut out = Vec::with_capacity(len);
+    let mut input = data[8..].iter().cloned();
+    let mut next = || input.next().ok_or("Compressed code is truncated");
+
+    while out.len() < len {
+        let b = next()?;
+        if b == 0x00 {
+            out.push(next()?);
+        } else if b <= 0x3b {
+            out.push(OLD_CODE_TABLE[b as usize - 1]);
+        } else {
+            let b2 = next()?;
+            let offset = (b as usize - 0x3c) * 16 + (b2 as usize & 0xf);
+            let count = (b2 as usize >> 4) + 2;
+            copy_back(&mut out, offset, count)?;
+        }
+    }
+
+    out.truncate(len);
+    Ok(out)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bit(&mut self) -> Result<u32, Box<dyn Error>> {
+        let byte = self
+            .data
+            .get(self.pos / 8)
+            .ok_or("Compressed code is truncated")?;
+        let bit = (byte >> (self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit as u32)
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, Box<dyn Error>> {
+        let mut value = 0;
+        for i in 0..n {
+            value |= self.bit()? << i;
+        }
+        Ok(value)
+    }
+}
+
+// Header, 2 byte big-endian decompressed length, 2 byte big-endian compressed
+// length. Then a little-endian bit stream of move-to-front coded literals and
+// back references.
+fn decompress_pxa(data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let len = read_len(data)?;
+    let mut out = Vec::with_capacity(len);
+    let mut mtf: Vec<u8> = (0..=255).collect();
+    let mut reader = BitReader {
+        data: &data[8..],
+        pos: 0,
+    };
+
+    while out.len() < len {
+        if reader.bit()? == 1 {
+            let mut extra = 0;
+            while reader.bit()? == 1 {
+                extra += 1;
+                if extra > 4 {
+                    return Err("Invalid literal in compressed code".into());
+                }
+            }
+            let index = (reader.bits(4 + extra)? + (((1 << extra) - 1) << 4)) as usize;
+            if index >= mtf.len() {
+                return Err("Invalid literal in compressed code".into());
+            }
+            let c = mtf.remove(index);
+            mtf.insert(0, c);
+            out.push(c);
+        } else {
+            let offset_bits = if reader.bit()? == 1 {
+                if reader.bit()? == 1 {
+                    5
+                } else {
+                    10
+                }
+            } else {
+                15
+            };
+            let offset = reader.bits(offset_bits)? as usize + 1;
+
+            if offset_bits == 10 && offset == 1 {
+                // An uncompressed run, terminated by a zero byte.
+                loop {
+                    let c = reader.bits(8)? as u8;
+                    if c == 0 {
+                        break;
+                    }
+                    out.push(c);
+                }
+            } else {
+                let mut count = 3;
+                loop {
+                    let part = reader.bits(3)?;
+                    count += part as usize;
+                    if part != 7 {
+                        break;
+                    }
+                }
+                copy_back(&mut out, offset, count)?;
+            }
+        }
+    }
+
+    out.truncate(len);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hides `rom` in a 160x205 image the way PICO-8 does, with noise in the
+    // high bits standing in for the label art.
+    fn encode_p8_png(rom: &[u8]) -> Vec<u8> {
+        let mut pixels = Vec::new();
+        for i in 0..(CART_WIDTH * CART_HEIGHT) as usize {
+            let b = rom.get(i).cloned().unwrap_or(0);
+            let noise = (i * 37 % 64) as u8;
+            pixels.push(noise << 2 | (b >> 4) & 3);
+            pixels.push(noise << 2 | (b >> 2) & 3);
+            pixels.push(noise << 2 | b & 3);
+            pixels.push(0xfc | b >> 6);
+        }
+
+        let mut png_bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png_bytes, CART_WIDTH, CART_HEIGHT);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&pixels).unwrap();
+        }
+        png_bytes
+    }
+
+    fn sample_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; CODE_END];
+        // Sprite 1 gets a diagonal line of color 7.
+        for i in 0..8 {
+            rom[i * 64 + 4 + i / 2] = if i % 2 == 0 { 0x07 } else { 0x70 };
+        }
+        rom[0x2000] = 1; // map cell (0, 0)
+        rom[0x3001] = 0x81; // sprite 1 flags
+        rom[0x3100] = 0x01; // music 0 plays sfx 1 on channel 0...
+        rom[0x3101] = 0x42; // ...channel 1 is off
+        rom[0x3102] = 0x43;
+        rom[0x3103] = 0x44;
+        rom[0x3200 + 68 + 65] = 16; // sfx 1 speed
+        rom[CODE_ADDR..CODE_ADDR + code.len()].copy_from_slice(code);
+        rom
+    }
+
+    struct BitWriter {
+        bytes: Vec<u8>,
+        pos: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, n: u32) {
+            for i in 0..n {
+                if self.pos / 8 == self.bytes.len() {
+                    self.bytes.push(0);
+                }
+                self.bytes[self.pos / 8] |= (((value >> i) & 1) as u8) << (self.pos % 8);
+                self.pos += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn extracts_rom_from_png() {
+        let rom = sample_rom(b"print(1)");
+        let cart = decode_p8_png(&encode_p8_png(&rom)).unwrap();
+
+        assert_eq!(cart.rom[..], rom[..ROM_SIZE]);
+        assert_eq!(cart.gfx_pixel(8, 0), 7);
+        assert_eq!(cart.gfx_pixel(9, 1), 7);
+        assert_eq!(cart.gfx_pixel(9, 0), 0);
+        assert_eq!(cart.map_cell(0, 0), 1);
+        assert_eq!(cart.sprite_flags()[1], 0x81);
+        assert_eq!(cart.music(0).channels, [Some(1), None, None, None]);
+        assert_eq!(cart.sfx(1).speed, 16);
+        assert_eq!(cart.code, "print(1)");
+    }
+
+    // tests/fixtures has the same small cart as a .p8 and a .p8.png, with
+    // pxa compressed code in the image.
+    #[test]
+    fn decodes_the_sample_cart() {
+        let png = decode_p8_png(include_bytes!("../tests/fixtures/hello.p8.png")).unwrap();
+        let text = P8Cart::from_p8_text(include_str!("../tests/fixtures/hello.p8")).unwrap();
+
+        assert!(png.code.starts_with("-- hello\n-- a small test cart\n"));
+        assert!(png.code.contains(" print(\"hello pico-8\",40,20,7)\nend\n"));
+        assert_eq!(png.code, text.code);
+
+        // Sprite 1 is a smiley, sprite 2 a brick.
+        assert_eq!((png.gfx_pixel(8, 0), png.gfx_pixel(10, 0)), (0, 0xa));
+        assert_eq!(png.gfx_pixel(10, 2), 0);
+        assert_eq!((png.gfx_pixel(16, 0), png.gfx_pixel(23, 0)), (4, 5));
+        assert_eq!(png.gfx_pixel(16, 2), 5);
+        assert_eq!(png.sprite_flags()[2], 1);
+        assert_eq!((png.map_cell(0, 3), png.map_cell(15, 3)), (2, 2));
+        assert_eq!(png.map_cell(16, 3), 0);
+        assert_eq!((png.map_cell(5, 2), png.map_cell(4, 2)), (2, 0));
+
+        let sfx = png.sfx(0);
+        assert_eq!(sfx.speed, 8);
+        let note = &sfx.notes[3];
+        assert_eq!((note.pitch, note.waveform, note.effect), (0x24, 1, 3));
+        assert_eq!(png.music(0).channels, [Some(0), None, None, None]);
+
+        assert_eq!(png.rom[..], text.rom[..]);
+    }
+
+    #[test]
+    fn rejects_wrong_size_image() {
+        let mut png_bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png_bytes, 8, 8);
+            encoder.set_color(png::ColorType::Rgba);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0; 8 * 8 * 4]).unwrap();
+        }
+        assert!(decode_p8_png(&png_bytes).is_err());
+    }
+
+    #[test]
+    fn decodes_old_compressed_code() {
+        // "a=1\na=1": 'a' is a literal, the rest come from the table, then
+        // a back reference copies 3 bytes from 4 back.
+        let mut code = OLD_HEADER.to_vec();
+        code.extend_from_slice(&[0, 7, 0, 0]);
+        code.extend_from_slice(&[0x00, b'a', 0x33, 0x04, 0x01]);
+        code.extend_from_slice(&[0x3c, 0x14]);
+
+        let rom = sample_rom(&code);
+        let cart = decode_p8_png(&encode_p8_png(&rom)).unwrap();
+        assert_eq!(cart.code, "a=1\na=1");
+    }
+
+    #[test]
+    fn decodes_pxa_code() {
+        let mut writer = BitWriter {
+            bytes: Vec::new(),
+            pos: 0,
+        };
+        // Literals 'a', 'b', 'c' sit at move-to-front indices 97, 98, 99,
+        // which need two extra unary bits: index = 48 + 6 bit value.
+        for index in [97, 98, 99].iter() {
+            writer.bits(1, 1);
+            writer.bits(0b011, 3);
+            writer.bits(index - 48, 6);
+        }
+        // Back reference with a 5 bit offset of 3, copying 3 + 0 bytes.
+        writer.bits(0, 1);
+        writer.bits(0b11, 2);
+        writer.bits(2, 5);
+        writer.bits(0, 3);
+        // An uncompressed run of "!", terminated by a zero.
+        writer.bits(0, 1);
+        writer.bits(0b01, 2);
+        writer.bits(0, 10);
+        writer.bits(b'!' as u32, 8);
+        writer.bits(0, 8);
+
+        let mut code = PXA_HEADER.to_vec();
+        code.extend_from_slice(&7u16.to_be_bytes());
+        code.extend_from_slice(&((8 + writer.bytes.len()) as u16).to_be_bytes());
+        code.extend_from_slice(&writer.bytes);
+
+        let rom = sample_rom(&code);
+        let cart = decode_p8_png(&encode_p8_png(&rom)).unwrap();
+        assert_eq!(cart.code, "abcabc!");
+    }
+
+    #[test]
+    fn rejects_bad_back_reference() {
+        let mut code = OLD_HEADER.to_vec();
+        code.extend_from_slice(&[0, 4, 0, 0, 0x3c, 0x25]);
+        assert!(decode_code(&code).is_err());
+    }
+}
@@ -0,0 +1,604 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::collision::has_flag;
+use crate::tilemap::TileMap;
+
+/*
+Pathfinding over a grid of cells the size of the map.
+
+Walkability comes from a cost function: None means the cell is blocked, and a
+number scales the cost of stepping into the cell (1.0 for normal ground, more
+for mud and so on). Costs below 1.0 make A* paths no longer the shortest,
+since its estimate assumes every cell costs at least 1.0.
+
+Searches can be run a few iterations at a time, so a frame can spend a fixed
+budget on them and carry on next frame. One iteration takes one cell off the
+open list.
+*/
+
+pub const CELL_SIZE: f32 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    AStar,
+    // Explores evenly in every direction, slower but doesn't need the goal to
+    // guide it.
+    Dijkstra,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connectivity {
+    Four,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathOptions {
+    pub algorithm: Algorithm,
+    pub connectivity: Connectivity,
+    pub straight_cost: f32,
+    pub diagonal_cost: f32,
+    // Whether a diagonal step may pass a blocked cell at its side.
+    pub cut_corners: bool,
+}
+
+impl Default for PathOptions {
+    fn default() -> PathOptions {
+        PathOptions {
+            algorithm: Algorithm::AStar,
+            connectivity: Connectivity::Four,
+            straight_cost: 1.0,
+            diagonal_cost: std::f32::consts::SQRT_2,
+            cut_corners: false,
+        }
+    }
+}
+
+impl PathOptions {
+    // Step costs must be above 0, or searches could loop around cells that
+    // make a path cheaper every time.
+    pub fn validate(&self) -> Result<(), String> {
+        let costs = [("straight_cost", self.straight_cost), ("diagonal_cost", self.diagonal_cost)];
+        for (name, cost) in &costs {
+            if !(cost.is_finite() && *cost > 0.0) {
+                return Err(format!("{} must be above 0, got {}", name, cost));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathStatus {
+    Searching,
+    Found(Vec<(i32, i32)>),
+    NoPath,
+}
+
+const STRAIGHT: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+const DIAGONAL: [(i32, i32); 4] = [(1, 1), (-1, 1), (-1, -1), (1, -1)];
+
+// An open list entry, the heap pops the lowest priority first.
+#[derive(Debug, Clone, PartialEq)]
+struct Open {
+    priority: f32,
+    cell: usize,
+}
+
+impl Eq for Open {}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Open) -> Ordering {
+        other
+            .priority
+            .total_cmp(&self.priority)
+            .then_with(|| other.cell.cmp(&self.cell))
+    }
+}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Open) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The grid shared by searches and flow fields.
+#[derive(Debug, Clone)]
+struct Grid {
+    width: i32,
+    height: i32,
+    options: PathOptions,
+}
+
+impl Grid {
+    // A grid and its number of cells. Cells are numbered with i32s, so a grid
+    // with more cells than that is empty and every cell is off it.
+    fn new(width: u32, height: u32, options: PathOptions) -> (Grid, usize) {
+        let size = (width as usize)
+            .checked_mul(height as usize)
+            .filter(|size| *size <= i32::MAX as usize);
+        let (width, height, size) = match size {
+            Some(size) => (width as i32, height as i32, size),
+            None => (0, 0, 0),
+        };
+        (Grid { width, height, options }, size)
+    }
+
+    fn index(&self, (x, y): (i32, i32)) -> Option<usize> {
+        if x >= 0 && x < self.width && y >= 0 && y < self.height {
+            Some(y as usize * self.width as usize + x as usize)
+        } else {
+            None
+        }
+    }
+
+    fn cell(&self, index: usize) -> (i32, i32) {
+        (index as i32 % self.width, index as i32 / self.width)
+    }
+
+    // The cells next to `from` that can be stepped to, with the cost of the
+    // step before the cell's own cost.
+    fn steps(&self, from: (i32, i32), cost: &dyn Fn(i32, i32) -> Option<f32>) -> Vec<((i32, i32), f32)> {
+        let walkable = |(x, y): (i32, i32)| self.index((x, y)).is_some() && cost(x, y).is_some();
+
+        let mut steps = Vec::with_capacity(8);
+        for (dx, dy) in STRAIGHT {
+            steps.push(((from.0 + dx, from.1 + dy), self.options.straight_cost));
+        }
+        if self.options.connectivity == Connectivity::Eight {
+            for (dx, dy) in DIAGONAL {
+                let sides_open = walkable((from.0 + dx, from.1)) && walkable((from.0, from.1 + dy));
+                if self.options.cut_corners || sides_open {
+                    steps.push(((from.0 + dx, from.1 + dy), self.options.diagonal_cost));
+                }
+            }
+        }
+        steps.retain(|(to, _)| walkable(*to));
+        steps
+    }
+
+    // A lower bound on the cost from a to b, for A*.
+    fn estimate(&self, a: (i32, i32), b: (i32, i32)) -> f32 {
+        let dx = (a.0 - b.0).abs() as f32;
+        let dy = (a.1 - b.1).abs() as f32;
+        let straight = self.options.straight_cost;
+        match self.options.connectivity {
+            Connectivity::Four => (dx + dy) * straight,
+            Connectivity::Eight => {
+                let diagonal = self.options.diagonal_cost.min(straight * 2.0);
+                dx.min(dy) * diagonal + (dx - dy).abs() * straight
+            }
+        }
+    }
+}
+
+// A path search from one cell to another that can be run a budget at a time.
+#[derive(Debug, Clone)]
+pub struct PathSearch {
+    grid: Grid,
+    goal: (i32, i32),
+    open: BinaryHeap<Open>,
+    cost_so_far: Vec<f32>,
+    came_from: Vec<usize>,
+    closed: Vec<bool>,
+    status: PathStatus,
+}
+
+impl PathSearch {
+    pub fn new(width: u32, height: u32, start: (i32, i32), goal: (i32, i32), options: PathOptions) -> PathSearch {
+        let (grid, size) = Grid::new(width, height, options);
+        let mut search = PathSearch {
+            grid,
+            goal,
+            open: BinaryHeap::new(),
+            cost_so_far: vec![f32::INFINITY; size],
+            came_from: vec![usize::MAX; size],
+            closed: vec![false; size],
+            status: PathStatus::Searching,
+        };
+
+        match (search.grid.index(start), search.grid.index(goal)) {
+            (Some(start_index), Some(_)) => {
+                search.cost_so_far[start_index] = 0.0;
+                search.open.push(Open {
+                    priority: 0.0,
+                    cell: start_index,
+                });
+            }
+            _ => search.status = PathStatus::NoPath,
+        }
+        search
+    }
+
+    pub fn status(&self) -> &PathStatus {
+        &self.status
+    }
+
+    // Runs up to `budget` iterations and returns where the search is at.
+    pub fn step(&mut self, budget: usize, cost: &dyn Fn(i32, i32) -> Option<f32>) -> &PathStatus {
+        if self.status != PathStatus::Searching {
+            return &self.status;
+        }
+        if cost(self.goal.0, self.goal.1).is_none() {
+            self.status = PathStatus::NoPath;
+            return &self.status;
+        }
+
+        for _ in 0..budget {
+            let current = match self.open.pop() {
+                Some(open) => open.cell,
+                None => {
+                    self.status = PathStatus::NoPath;
+                    return &self.status;
+                }
+            };
+            if self.closed[current] {
+                continue;
+            }
+            self.closed[current] = true;
+
+            let cell = self.grid.cell(current);
+            if cell == self.goal {
+                self.status = PathStatus::Found(self.path_to(current));
+                return &self.status;
+            }
+
+            for (next, step_cost) in self.grid.steps(cell, cost) {
+                let index = self.grid.index(next).unwrap();
+                let next_cost = self.cost_so_far[current] + step_cost * cost(next.0, next.1).unwrap_or(1.0);
+                if next_cost < self.cost_so_far[index] {
+                    self.cost_so_far[index] = next_cost;
+                    self.came_from[index] = current;
+                    let estimate = match self.grid.options.algorithm {
+                        Algorithm::AStar => self.grid.estimate(next, self.goal),
+                        Algorithm::Dijkstra => 0.0,
+                    };
+                    self.open.push(Open {
+                        priority: next_cost + estimate,
+                        cell: index,
+                    });
+                }
+            }
+        }
+        &self.status
+    }
+
+    // The cells from the start to `end`, both included.
+    fn path_to(&self, end: usize) -> Vec<(i32, i32)> {
+        let mut path = vec![self.grid.cell(end)];
+        let mut current = end;
+        while self.came_from[current] != usize::MAX {
+            current = self.came_from[current];
+            path.push(self.grid.cell(current));
+        }
+        path.reverse();
+        path
+    }
+}
+
+// Runs a whole search at once. The path includes the start and the goal.
+pub fn find_path(
+    width: u32,
+    height: u32,
+    start: (i32, i32),
+    goal: (i32, i32),
+    options: PathOptions,
+    cost: &dyn Fn(i32, i32) -> Option<f32>,
+) -> Option<Vec<(i32, i32)>> {
+    let mut search = PathSearch::new(width, height, start, goal, options);
+    match search.step(usize::MAX, cost) {
+        PathStatus::Found(path) => Some(path.clone()),
+        _ => None,
+    }
+}
+
+// Every cell's distance to the nearest goal and the step to take towards it,
+// for many agents heading to the same place. Ignores options.algorithm.
+#[derive(Debug, Clone)]
+pub struct FlowField {
+    grid: Grid,
+    open: BinaryHeap<Open>,
+    distance: Vec<f32>,
+    toward: Vec<usize>,
+    closed: Vec<bool>,
+}
+
+impl FlowField {
+    pub fn new(width: u32, height: u32, goals: &[(i32, i32)], options: PathOptions) -> FlowField {
+        let (grid, size) = Grid::new(width, height, options);
+        let mut field = FlowField {
+            grid,
+            open: BinaryHeap::new(),
+            distance: vec![f32::INFINITY; size],
+            toward: vec![usize::MAX; size],
+            closed: vec![false; size],
+        };
+        for goal in goals {
+            if let Some(index) = field.grid.index(*goal) {
+                field.distance[index] = 0.0;
+                field.open.push(Open {
+                    priority: 0.0,
+                    cell: index,
+                });
+            }
+        }
+        field
+    }
+
+    // Spreads out from the goals for up to `budget` iterations, true once
+    // every reachable cell is done.
+    pub fn step(&mut self, budget: usize, cost: &dyn Fn(i32, i32) -> Option<f32>) -> bool {
+        for _ in 0..budget {
+            let current = match self.open.pop() {
+                Some(open) => open.cell,
+                None => return true,
+            };
+            if self.closed[current] {
+                continue;
+            }
+            self.closed[current] = true;
+
+            // Moving from `next` to `current` means entering `current`.
+            let cell = self.grid.cell(current);
+            let enter_cost = cost(cell.0, cell.1).unwrap_or(1.0);
+            for (next, step_cost) in self.grid.steps(cell, cost) {
+                let index = self.grid.index(next).unwrap();
+                let next_distance = self.distance[current] + step_cost * enter_cost;
+                if next_distance < self.distance[index] {
+                    self.distance[index] = next_distance;
+                    self.toward[index] = current;
+                    self.open.push(Open {
+                        priority: next_distance,
+                        cell: index,
+                    });
+                }
+            }
+        }
+        self.open.is_empty()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.open.is_empty()
+    }
+
+    // The cost from a cell to the nearest goal, None if it can't get there
+    // (or hasn't been reached yet).
+    pub fn distance(&self, x: i32, y: i32) -> Option<f32> {
+        let index = self.grid.index((x, y))?;
+        let distance = self.distance[index];
+        if distance.is_finite() {
+            Some(distance)
+        } else {
+            None
+        }
+    }
+
+    // The cell to step to from (x, y), None at a goal or where no goal can be
+    // reached.
+    pub fn next(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        let index = self.grid.index((x, y))?;
+        match self.toward[index] {
+            usize::MAX => None,
+            toward => Some(self.grid.cell(toward)),
+        }
+    }
+
+    // Like next, as a direction of -1, 0 or 1 on each axis.
+    pub fn direction(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        self.next(x, y).map(|(nx, ny)| (nx - x, ny - y))
+    }
+}
+
+// A cost function that blocks cells on a layer whose sprite has flag f.
+pub fn flag_cost<'m>(map: &'m TileMap, flags: &'m [u8; 256], layer: usize, f: u8) -> impl Fn(i32, i32) -> Option<f32> + 'm {
+    move |x, y| {
+        if x < 0 || y < 0 {
+            return None;
+        }
+        let sprite = map.get(x as u32, y as u32, layer)?;
+        if has_flag(flags[sprite as usize], f) {
+            None
+        } else {
+            Some(1.0)
+        }
+    }
+}
+
+// The middle of each cell of a path, in pixels.
+pub fn path_to_pixels(path: &[(i32, i32)]) -> Vec<(f32, f32)> {
+    path.iter()
+        .map(|(x, y)| {
+            (
+                *x as f32 * CELL_SIZE + CELL_SIZE / 2.0,
+                *y as f32 * CELL_SIZE + CELL_SIZE / 2.0,
+            )
+        })
+        .collect()
+}
+
+// The cell a pixel is in.
+pub fn pixel_to_cell(x: f32, y: f32) -> (i32, i32) {
+    ((x / CELL_SIZE).floor() as i32, (y / CELL_SIZE).floor() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The cost of a cell in rows of characters: # is blocked, ~ costs 5,
+    // anything else costs 1.
+    fn row_cost(rows: &[&str], x: i32, y: i32) -> Option<f32> {
+        match rows[y as usize].as_bytes()[x as usize] {
+            b'#' => None,
+            b'~' => Some(5.0),
+            _ => Some(1.0),
+        }
+    }
+
+    fn path_cost(path: &[(i32, i32)]) -> usize {
+        path.len() - 1
+    }
+
+    #[test]
+    fn finds_a_path_around_walls() {
+        let rows = [".....", ".###.", "...#.", "##.#.", "....."];
+        let cost = |x, y| row_cost(&rows, x, y);
+        for algorithm in [Algorithm::AStar, Algorithm::Dijkstra] {
+            let options = PathOptions {
+                algorithm,
+                ..PathOptions::default()
+            };
+            let path = find_path(5, 5, (0, 2), (4, 4), options, &cost).unwrap();
+            assert_eq!(path.first(), Some(&(0, 2)));
+            assert_eq!(path.last(), Some(&(4, 4)));
+            assert_eq!(path_cost(&path), 6);
+            for pair in path.windows(2) {
+                let (a, b) = (pair[0], pair[1]);
+                assert_eq!((a.0 - b.0).abs() + (a.1 - b.1).abs(), 1);
+                assert!(cost(b.0, b.1).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_step_costs_not_above_zero() {
+        assert!(PathOptions::default().validate().is_ok());
+        for cost in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let straight = PathOptions {
+                straight_cost: cost,
+                ..PathOptions::default()
+            };
+            let diagonal = PathOptions {
+                diagonal_cost: cost,
+                ..PathOptions::default()
+            };
+            assert!(straight.validate().is_err());
+            assert!(diagonal.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn no_path_when_walled_off() {
+        let rows = ["..#..", "..#..", "..#.."];
+        let cost = |x, y| row_cost(&rows, x, y);
+        assert_eq!(find_path(5, 3, (0, 0), (4, 0), PathOptions::default(), &cost), None);
+        // Blocked or off the map goals.
+        assert_eq!(find_path(5, 3, (0, 0), (2, 0), PathOptions::default(), &cost), None);
+        assert_eq!(find_path(5, 3, (0, 0), (9, 0), PathOptions::default(), &cost), None);
+        assert_eq!(
+            find_path(5, 3, (1, 1), (1, 1), PathOptions::default(), &cost),
+            Some(vec![(1, 1)])
+        );
+    }
+
+    #[test]
+    fn diagonals_and_corners() {
+        let eight = PathOptions {
+            connectivity: Connectivity::Eight,
+            ..PathOptions::default()
+        };
+        let open = |_, _| Some(1.0);
+        let path = find_path(4, 4, (0, 3), (3, 0), eight, &open).unwrap();
+        assert_eq!(path, vec![(0, 3), (1, 2), (2, 1), (3, 0)]);
+        let path = find_path(4, 4, (0, 3), (3, 0), PathOptions::default(), &open).unwrap();
+        assert_eq!(path_cost(&path), 6);
+
+        // Stepping from (0, 1) to (1, 0) cuts the corner of (1, 1).
+        let rows = ["....", ".#..", "....", "...."];
+        let cost = |x, y| row_cost(&rows, x, y);
+        let path = find_path(4, 4, (0, 1), (1, 0), eight, &cost).unwrap();
+        assert_eq!(path, vec![(0, 1), (0, 0), (1, 0)]);
+        let cutting = PathOptions {
+            cut_corners: true,
+            ..eight
+        };
+        let path = find_path(4, 4, (0, 1), (1, 0), cutting, &cost).unwrap();
+        assert_eq!(path, vec![(0, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn costs_steer_around_slow_cells() {
+        let rows = [".~~~.", ".....", "....."];
+        let cost = |x, y| row_cost(&rows, x, y);
+        let path = find_path(5, 3, (0, 0), (4, 0), PathOptions::default(), &cost).unwrap();
+        assert!(!path.contains(&(2, 0)));
+        assert_eq!(path_cost(&path), 6);
+    }
+
+    #[test]
+    fn searches_can_be_spread_over_frames() {
+        let open = |_, _| Some(1.0);
+        let options = PathOptions {
+            algorithm: Algorithm::Dijkstra,
+            ..PathOptions::default()
+        };
+        let mut search = PathSearch::new(8, 4, (0, 0), (7, 3), options);
+        let mut frames = 0;
+        while *search.step(4, &open) == PathStatus::Searching {
+            frames += 1;
+            assert!(frames < 100);
+        }
+        assert!(frames > 1);
+        match search.status() {
+            PathStatus::Found(path) => assert_eq!(path_cost(path), 10),
+            status => panic!("{:?}", status),
+        }
+    }
+
+    #[test]
+    fn flow_fields_point_to_the_nearest_goal() {
+        let rows = ["......", ".####.", "......"];
+        let cost = |x, y| row_cost(&rows, x, y);
+        let mut field = FlowField::new(6, 3, &[(0, 1), (5, 1)], PathOptions::default());
+        assert!(!field.step(3, &cost));
+        while !field.step(3, &cost) {}
+        assert!(field.is_done());
+
+        assert_eq!(field.distance(0, 1), Some(0.0));
+        assert_eq!(field.next(0, 1), None);
+        assert_eq!(field.distance(1, 0), Some(2.0));
+        assert_eq!(field.distance(4, 2), Some(2.0));
+        assert_eq!(field.distance(2, 1), None);
+        assert_eq!(field.direction(5, 0), Some((0, 1)));
+
+        // Following the field always gets to a goal.
+        let mut cell = (2, 0);
+        for _ in 0..10 {
+            match field.next(cell.0, cell.1) {
+                Some(next) => cell = next,
+                None => break,
+            }
+        }
+        assert!(cell == (0, 1) || cell == (5, 1));
+    }
+
+    #[test]
+    fn flag_costs_and_pixels() {
+        let mut map = TileMap::new(3, 1, 1);
+        map.set(1, 0, 0, 7);
+        let mut flags = [0; 256];
+        flags[7] = 1 << 2;
+        let cost = flag_cost(&map, &flags, 0, 2);
+        assert_eq!(cost(0, 0), Some(1.0));
+        assert_eq!(cost(1, 0), None);
+        assert_eq!(cost(-1, 0), None);
+        assert_eq!(flag_cost(&map, &flags, 0, 1)(1, 0), Some(1.0));
+        assert_eq!(flag_cost(&map, &[0xff; 256], 0, 8)(1, 0), Some(1.0));
+
+        assert_eq!(path_to_pixels(&[(0, 0), (2, 1)]), vec![(4.0, 4.0), (20.0, 12.0)]);
+        assert_eq!(pixel_to_cell(20.0, -1.0), (2, -1));
+    }
+
+    #[test]
+    fn grids_too_big_to_index_are_empty() {
+        let open = |_, _| Some(1.0);
+        let mut search = PathSearch::new(u32::MAX, u32::MAX, (0, 0), (1, 0), PathOptions::default());
+        assert_eq!(*search.step(10, &open), PathStatus::NoPath);
+        assert_eq!(find_path(1 << 31, 1, (0, 0), (1, 0), PathOptions::default(), &open), None);
+
+        let mut field = FlowField::new(1 << 16, 1 << 16, &[(0, 0)], PathOptions::default());
+        assert!(field.step(10, &open));
+        assert_eq!(field.distance(0, 0), None);
+    }
+}
@@ -0,0 +1,671 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use sdl2::mixer::{Channel, Chunk, Music};
+
+use crate::assets::Assets;
+use crate::audio::{AudioBackend, AudioConfig, SfxParams, SoundLibrary, SynthBank, CHANNEL_ALL, CHANNEL_ANY, MUSIC_STOP, SFX_RELEASE, SFX_STOP};
+use crate::logging::{ASSETS, AUDIO};
+use crate::mixer;
+use crate::mixer::{AudioBus, AudioSettings};
+use crate::sequencer;
+use crate::synth;
+
+// A synthesized sound: an sfx with its first note and note count, or the
+// song starting at a music pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SynthSound {
+    Sfx(usize, usize, Option<usize>),
+    Music(usize),
+}
+
+// A synthesized sound rendered for the mixer. A sound that loops plays its
+// intro once and then repeats the loop chunk, which update starts once the
+// intro has ended. SDL_mixer doesn't allow playing from its channel finished
+// callback, so the loop can start up to a frame late.
+struct SynthChunks {
+    intro: Option<Chunk>,
+    looped: Option<Chunk>,
+    intro_seconds: f32,
+    looped_seconds: f32,
+}
+
+// The music playing, by the name music() was given. Pattern music plays on a
+// mixer channel, recorded music on the mixer's music stream.
+struct PlayingMusic {
+    name: String,
+    channel: Option<i32>,
+    // Recorded music with loop points in the audio config: where it loops
+    // from and to, and when it would have started for its position now.
+    loop_points: Option<(f64, Option<f64>)>,
+    started: Instant,
+    // When the music was paused, if it is.
+    paused: Option<Instant>,
+}
+
+// What sfx() last started on a channel.
+struct PlayingSfx {
+    name: String,
+    // None for recorded sounds.
+    sound: Option<SynthSound>,
+    started: Instant,
+    // When the loop of a synth sound started, for releasing it.
+    loop_started: Option<Instant>,
+    volume: f32,
+    pan: f32,
+    priority: i32,
+}
+
+// A copy of part of a chunk, `offset` and `length` in milliseconds. The chunk
+// has to be in the format the mixer was opened with (it is once loaded).
+fn chunk_range(chunk: &Chunk, offset: u32, length: u32) -> Result<Chunk, String> {
+    let (frequency, _, channels) = sdl2::mixer::query_spec()?;
+    // 16 bit samples.
+    let frame_bytes = 2 * channels as usize;
+    let ms_frames = |ms: u32| ms as usize * frequency as usize / 1000;
+
+    let data = unsafe { std::slice::from_raw_parts((*chunk.raw).abuf, (*chunk.raw).alen as usize) };
+    let frames = data.len() / frame_bytes;
+    let start = ms_frames(offset).min(frames);
+    let end = if length == 0 { frames } else { (start + ms_frames(length)).min(frames) };
+
+    let samples: Vec<i16> = data[start * frame_bytes..end * frame_bytes]
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    Chunk::from_raw_buffer(samples.into_boxed_slice())
+}
+
+// Converts synthesized samples to a chunk in the format the mixer was opened
+// with. None for no samples.
+fn synth_chunk(samples: &[f32], channels: i32) -> Result<Option<Chunk>, String> {
+    if samples.is_empty() {
+        return Ok(None);
+    }
+    let mut buffer = Vec::with_capacity(samples.len() * channels as usize);
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        for _ in 0..channels {
+            buffer.push(value);
+        }
+    }
+    Chunk::from_raw_buffer(buffer.into_boxed_slice()).map(Some)
+}
+
+// Plays sound through SDL_mixer, which has to be open.
+pub struct SdlAudio {
+    sfx: HashMap<String, Chunk>,
+    music: HashMap<String, Music<'static>>,
+    // Parts of recorded sfx by name, offset and length.
+    sfx_ranges: HashMap<(String, u32, u32), Chunk>,
+    sfx_channels: HashMap<i32, PlayingSfx>,
+    synth_chunks: HashMap<SynthSound, SynthChunks>,
+    // Channels playing the intro of a synth sound that loops, and the sound.
+    synth_loops: HashMap<i32, SynthSound>,
+    playing_music: Option<PlayingMusic>,
+    // Channels of pattern music that is fading out.
+    music_fading: Vec<i32>,
+    // Music waiting for what it replaces to fade out, by name, fade and
+    // channel mask.
+    music_queued: Option<(String, u32, u32)>,
+    // Channels kept free from sfx on CHANNEL_ANY while music plays.
+    music_channelmask: u32,
+    settings: AudioSettings,
+    sounds: SoundLibrary,
+}
+
+impl SdlAudio {
+    // Loads the recorded music and sfx listed in the audio config.
+    pub fn new(assets: &Assets, config: &AudioConfig) -> Result<SdlAudio, Box<dyn Error>> {
+        let mut music = HashMap::new();
+        for entry in &config.music {
+            let track = assets
+                .load_music(&entry.path)
+                .map_err(|e| format!("Could not load music {}: {}", entry.path, e))?;
+            music.insert(entry.name.clone(), track);
+        }
+
+        let mut sfx = HashMap::new();
+        for entry in &config.sfx {
+            let chunk = assets
+                .load_chunk(&entry.path)
+                .map_err(|e| format!("Could not load sfx {}: {}", entry.path, e))?;
+            sfx.insert(entry.name.clone(), chunk);
+        }
+        info!(target: ASSETS, "Loaded {} music tracks and {} sfx", music.len(), sfx.len());
+
+        Ok(SdlAudio {
+            sfx,
+            music,
+            sfx_ranges: HashMap::new(),
+            sfx_channels: HashMap::new(),
+            synth_chunks: HashMap::new(),
+            synth_loops: HashMap::new(),
+            playing_music: None,
+            music_fading: Vec::new(),
+            music_queued: None,
+            music_channelmask: 0,
+            settings: AudioSettings::default(),
+            sounds: SoundLibrary::from_config(config),
+        })
+    }
+
+    // Renders a synth sound for the mixer, unless it already was.
+    fn render_synth(&mut self, synth: &SynthBank, sound: SynthSound) -> Result<(), String> {
+        if self.synth_chunks.contains_key(&sound) {
+            return Ok(());
+        }
+        let (frequency, _, channels) = sdl2::mixer::query_spec()?;
+        let rendered = match sound {
+            SynthSound::Sfx(n, offset, length) => {
+                synth::render_sfx(&synth.sfx, n, frequency as u32, offset, length)
+            }
+            SynthSound::Music(n) => {
+                sequencer::render_music(&synth.sfx, &synth.patterns, n, frequency as u32)
+            }
+        };
+        let chunks = SynthChunks {
+            intro: synth_chunk(&rendered.intro, channels)?,
+            looped: synth_chunk(&rendered.looped, channels)?,
+            intro_seconds: rendered.intro.len() as f32 / frequency as f32,
+            looped_seconds: rendered.looped.len() as f32 / frequency as f32,
+        };
+        self.synth_chunks.insert(sound, chunks);
+        Ok(())
+    }
+
+    // Plays a synth sound, fading it in over `fadems` if that isn't 0.
+    // Returns the channel it plays on.
+    fn play_synth(&mut self, synth: &SynthBank, sound: SynthSound, channel: i32, fadems: u32) -> Result<Option<i32>, String> {
+        self.render_synth(synth, sound)?;
+        self.synth_loops.remove(&channel);
+
+        let chunks = &self.synth_chunks[&sound];
+        let play = |chunk: &Chunk, loops: i32| {
+            if fadems > 0 {
+                Channel(channel).fade_in(chunk, loops, fadems as i32)
+            } else {
+                Channel(channel).play(chunk, loops)
+            }
+        };
+        let playing = match (&chunks.intro, &chunks.looped) {
+            (Some(intro), _) => play(intro, 0)?,
+            (None, Some(looped)) => play(looped, -1)?,
+            (None, None) => return Ok(None),
+        };
+        if let (Some(_), Some(_)) = (&chunks.intro, &chunks.looped) {
+            self.synth_loops.insert(playing.0, sound);
+        }
+        Ok(Some(playing.0))
+    }
+
+    // Starts the loops of synth sounds whose intro has ended. SDL_mixer drops
+    // a channel's effects when a chunk ends, so sfx are panned again first,
+    // music is never panned.
+    fn start_loops(&mut self) {
+        let ended: Vec<(i32, SynthSound)> = self
+            .synth_loops
+            .iter()
+            .filter(|(channel, _)| !Channel(**channel).is_playing())
+            .map(|(channel, sound)| (*channel, *sound))
+            .collect();
+        for (channel, sound) in ended {
+            self.synth_loops.remove(&channel);
+            let looped = match self.synth_chunks.get(&sound).and_then(|chunks| chunks.looped.as_ref()) {
+                Some(looped) => looped,
+                None => continue,
+            };
+            if let Some(playing) = self.sfx_channels.get(&channel) {
+                let (left, right) = mixer::panning(playing.pan);
+                if let Err(err) = Channel(channel).set_panning(left, right) {
+                    warn!(target: AUDIO, "Could not pan channel {}: {}", channel, err);
+                }
+            }
+            if let Err(err) = Channel(channel).play(looped, -1) {
+                warn!(target: AUDIO, "Could not loop channel {}: {}", channel, err);
+                continue;
+            }
+            if let Some(playing) = self.sfx_channels.get_mut(&channel) {
+                playing.loop_started = Some(Instant::now());
+            }
+        }
+    }
+
+    // A channel for an sfx played on CHANNEL_ANY: the first one that isn't
+    // playing or kept for music, or if they're all busy the one playing the
+    // sfx of lowest priority, the oldest of them, as long as that's no higher
+    // than `priority`. None if music or more important sounds have them all.
+    fn free_channel(&self, priority: i32) -> Option<i32> {
+        let channels = sdl2::mixer::allocate_channels(-1);
+        let unreserved: Vec<i32> = (0..channels)
+            .filter(|channel| *channel >= 32 || self.music_channelmask & (1 << channel) == 0)
+            .filter(|channel| !self.music_fading.contains(channel))
+            .collect();
+        if let Some(channel) = unreserved.iter().copied().find(|channel| !Channel(*channel).is_playing()) {
+            return Some(channel);
+        }
+        unreserved
+            .iter()
+            .map(|channel| match self.sfx_channels.get(channel) {
+                Some(playing) => (playing.priority, Some(playing.started), *channel),
+                None => (i32::MIN, None, *channel),
+            })
+            .filter(|(playing, _, _)| *playing <= priority)
+            .min()
+            .map(|(_, _, channel)| channel)
+    }
+
+    // The channel of the oldest instance of an sfx when it's already playing
+    // as many times as its max_instances allows.
+    fn instance_to_replace(&self, audio: &str) -> Option<i32> {
+        let max = self.sounds.sfx_meta(audio)?.max_instances? as usize;
+        let instances: Vec<(Instant, i32)> = self
+            .sfx_channels
+            .iter()
+            .filter(|(channel, playing)| playing.name == audio && self.sfx_playing(**channel).is_some())
+            .map(|(channel, playing)| (playing.started, *channel))
+            .collect();
+        if instances.len() < max {
+            return None;
+        }
+        instances.iter().min().map(|(_, channel)| *channel)
+    }
+
+    // Plays a recorded sfx, all of it or part of it.
+    fn play_recorded(&mut self, audio: &str, channel: i32, offset: u32, length: u32) -> Result<i32, String> {
+        self.synth_loops.remove(&channel);
+        if offset == 0 && length == 0 {
+            return Ok(Channel(channel).play(&self.sfx[audio], 0)?.0);
+        }
+        let key = (audio.to_string(), offset, length);
+        if !self.sfx_ranges.contains_key(&key) {
+            let range = chunk_range(&self.sfx[audio], offset, length)?;
+            self.sfx_ranges.insert(key.clone(), range);
+        }
+        Ok(Channel(channel).play(&self.sfx_ranges[&key], 0)?.0)
+    }
+
+    fn stop_channel(&mut self, channel: i32) {
+        self.synth_loops.remove(&channel);
+        self.sfx_channels.remove(&channel);
+        Channel(channel).halt();
+    }
+
+    // Stops a looping sound at the end of its current pass through the loop.
+    fn release_channel(&mut self, channel: i32) {
+        let playing = match self.sfx_channels.get(&channel) {
+            Some(playing) => playing,
+            None => return,
+        };
+        if self.synth_loops.remove(&channel).is_some() {
+            // Still in the intro, which ends on its own.
+            return;
+        }
+        let chunks = match playing.sound.and_then(|sound| self.synth_chunks.get(&sound)) {
+            Some(chunks) if chunks.looped.is_some() => chunks,
+            _ => return,
+        };
+        let looping = match playing.loop_started {
+            Some(loop_started) => loop_started.elapsed().as_secs_f32(),
+            None => playing.started.elapsed().as_secs_f32() - chunks.intro_seconds,
+        };
+        let remaining = chunks.looped_seconds - looping.max(0.0) % chunks.looped_seconds;
+        Channel(channel).expire((remaining * 1000.0).ceil() as i32);
+    }
+
+    // The sfx channels to stop or release, every one of them for CHANNEL_ANY.
+    fn sfx_channels_for(&self, channel: i32) -> Vec<i32> {
+        if channel == CHANNEL_ANY {
+            self.sfx_channels.keys().copied().collect()
+        } else {
+            vec![channel]
+        }
+    }
+
+    // Fades out the music playing, or stops it when `fadems` is 0, and
+    // forgets any music waiting to start.
+    fn stop_music(&mut self, fadems: u32) {
+        self.music_queued = None;
+        self.music_channelmask = 0;
+        let playing = match self.playing_music.take() {
+            Some(playing) => playing,
+            None => return,
+        };
+        match playing.channel {
+            Some(channel) => {
+                // The loop mustn't start once the fade ends the intro.
+                self.synth_loops.remove(&channel);
+                if fadems > 0 {
+                    Channel(channel).fade_out(fadems as i32);
+                    self.music_fading.push(channel);
+                } else {
+                    Channel(channel).halt();
+                }
+            }
+            None => {
+                if fadems == 0 {
+                    Music::halt();
+                } else if let Err(err) = Music::fade_out(fadems as i32) {
+                    warn!(target: AUDIO, "Could not fade out music {}: {}", playing.name, err);
+                }
+            }
+        }
+    }
+
+    // Starts music, fading it in over `fadems`. Recorded music waits in the
+    // queue for the recorded track it replaces to fade out. Pattern music
+    // takes a channel in `channelmask` that isn't fading out, or when the
+    // fading music has them all, the lowest other channel that isn't, so it
+    // always crossfades.
+    fn start_music(&mut self, synth: &SynthBank, audio: &str, fadems: u32, channelmask: u32) -> Result<(), String> {
+        if let Some(music) = self.music.get(audio) {
+            if Music::is_playing() {
+                debug!(target: AUDIO, "Music {} waits for the music fading out", audio);
+                self.music_queued = Some((audio.to_string(), fadems, channelmask));
+                return Ok(());
+            }
+            Music::set_volume(self.settings.mix_volume(AudioBus::Music, self.sounds.music_volume(audio)));
+            // Music with loop points plays once and is looped by update_music.
+            let loop_points = self.sounds.music_meta(audio).and_then(|meta| match (meta.loop_start, meta.loop_end) {
+                (None, None) => None,
+                (start, end) => Some((start.unwrap_or(0.0), end)),
+            });
+            let loops = if loop_points.is_some() { 1 } else { -1 };
+            if fadems > 0 {
+                music.fade_in(loops, fadems as i32)?;
+            } else {
+                music.play(loops)?;
+            }
+            self.music_channelmask = channelmask;
+            debug!(target: AUDIO, "Playing music {}", audio);
+            self.playing_music = Some(PlayingMusic {
+                name: audio.to_string(),
+                channel: None,
+                loop_points,
+                started: Instant::now(),
+                paused: None,
+            });
+            return Ok(());
+        }
+
+        let n = match synth.pattern_number(audio) {
+            Some(n) => n,
+            None => return Err(format!("no music found: {}", audio)),
+        };
+        // Pattern music needs a channel, 0 unless the mask says otherwise.
+        let channelmask = if channelmask == 0 { 1 } else { channelmask };
+        let channels = sdl2::mixer::allocate_channels(-1).min(32);
+        let unfaded: Vec<i32> = (0..channels).filter(|channel| !self.music_fading.contains(channel)).collect();
+        let channel = unfaded
+            .iter()
+            .find(|channel| channelmask & (1 << **channel) != 0)
+            .or_else(|| unfaded.iter().find(|channel| !Channel(**channel).is_playing()))
+            .or_else(|| unfaded.first())
+            .copied();
+        let channel = match channel {
+            Some(channel) => channel,
+            None => {
+                debug!(target: AUDIO, "Music {} waits for a channel that isn't fading out", audio);
+                self.music_queued = Some((audio.to_string(), fadems, channelmask));
+                return Ok(());
+            }
+        };
+        self.stop_channel(channel);
+        self.music_channelmask = channelmask | (1 << channel);
+        Channel(channel).set_volume(self.settings.mix_volume(AudioBus::Music, self.sounds.music_volume(audio)));
+        Channel(channel).unset_panning()?;
+        if let Some(channel) = self.play_synth(synth, SynthSound::Music(n), channel, fadems)? {
+            debug!(target: AUDIO, "Playing music {} on channel {}", audio, channel);
+            self.playing_music = Some(PlayingMusic {
+                name: audio.to_string(),
+                channel: Some(channel),
+                loop_points: None,
+                started: Instant::now(),
+                paused: None,
+            });
+        }
+        Ok(())
+    }
+
+    // Jumps back to the loop start of recorded music with loop points once
+    // it reaches its loop end, or the end of the track. The position is the
+    // time since it started, less any time paused, as SDL_mixer doesn't give
+    // it, and it's checked once a frame, so the jump can be a frame late.
+    fn loop_music(&mut self) {
+        let playing = match &mut self.playing_music {
+            Some(playing) if playing.channel.is_none() => playing,
+            _ => return,
+        };
+        let (loop_start, loop_end) = match playing.loop_points {
+            Some(points) => points,
+            None => return,
+        };
+        if Music::is_paused() {
+            playing.paused.get_or_insert_with(Instant::now);
+            return;
+        }
+        if let Some(paused) = playing.paused.take() {
+            playing.started += paused.elapsed();
+        }
+        let position = playing.started.elapsed().as_secs_f64();
+        let ended = !Music::is_playing();
+        if !ended && loop_end.is_none_or(|end| position < end) {
+            return;
+        }
+        if ended {
+            if let Err(err) = self.music[&playing.name].play(1) {
+                warn!(target: AUDIO, "Could not loop music {}: {}", playing.name, err);
+                return;
+            }
+        }
+        Music::rewind();
+        if let Err(err) = Music::set_pos(loop_start) {
+            warn!(target: AUDIO, "Could not loop music {}: {}", playing.name, err);
+        }
+        let now = Instant::now();
+        playing.started = now.checked_sub(Duration::from_secs_f64(loop_start)).unwrap_or(now);
+    }
+
+    // Forgets music that ended and channels that finished fading out, and
+    // starts queued music once it can.
+    fn update_music(&mut self, synth: &SynthBank) {
+        self.loop_music();
+        self.music_fading.retain(|channel| Channel(*channel).is_playing());
+        let ended = match &self.playing_music {
+            Some(PlayingMusic { channel: Some(channel), .. }) => {
+                !Channel(*channel).is_playing() && !self.synth_loops.contains_key(channel)
+            }
+            Some(PlayingMusic { channel: None, .. }) => !Music::is_playing(),
+            None => false,
+        };
+        if ended {
+            self.playing_music = None;
+            self.music_channelmask = 0;
+        }
+        if let Some((audio, fadems, channelmask)) = self.music_queued.take() {
+            if let Err(err) = self.start_music(synth, &audio, fadems, channelmask) {
+                warn!(target: AUDIO, "Could not play music {}: {}", audio, err);
+            }
+        }
+    }
+}
+
+impl AudioBackend for SdlAudio {
+    fn sfx(&mut self, synth: &SynthBank, audio: &str, params: SfxParams) -> Option<i32> {
+        let SfxParams {
+            channel,
+            offset,
+            length,
+            volume,
+            pan,
+        } = params;
+        if audio == SFX_STOP {
+            for channel in self.sfx_channels_for(channel) {
+                self.stop_channel(channel);
+            }
+            return None;
+        }
+        if audio == SFX_RELEASE {
+            for channel in self.sfx_channels_for(channel) {
+                self.release_channel(channel);
+            }
+            return None;
+        }
+        if channel == CHANNEL_ALL {
+            let playing: Vec<i32> = self
+                .sfx_channels
+                .iter()
+                .filter(|(_, playing)| playing.name == audio)
+                .map(|(channel, _)| *channel)
+                .collect();
+            for channel in playing {
+                self.stop_channel(channel);
+            }
+            return None;
+        }
+
+        let priority = self.sounds.priority(audio);
+        let channel = if channel == CHANNEL_ANY {
+            match self.instance_to_replace(audio).or_else(|| self.free_channel(priority)) {
+                Some(channel) => channel,
+                None => {
+                    debug!(target: AUDIO, "No channel for sfx {}, music or more important sfx have them all", audio);
+                    return None;
+                }
+            }
+        } else {
+            channel
+        };
+        // Set before playing so fades go up to it.
+        Channel(channel).set_volume(self.settings.mix_volume(AudioBus::Sfx, volume));
+        let (left, right) = mixer::panning(pan);
+        if let Err(err) = Channel(channel).set_panning(left, right) {
+            warn!(target: AUDIO, "Could not pan channel {}: {}", channel, err);
+        }
+
+        let (sound, result) = if self.sfx.contains_key(audio) {
+            (None, self.play_recorded(audio, channel, offset, length).map(Some))
+        } else {
+            match synth.sfx_number(audio) {
+                Some(n) => {
+                    let length = if length == 0 { None } else { Some(length as usize) };
+                    let sound = SynthSound::Sfx(n, offset as usize, length);
+                    (Some(sound), self.play_synth(synth, sound, channel, 0))
+                }
+                None => {
+                    warn!(target: AUDIO, "No sfx named {}", audio);
+                    return None;
+                }
+            }
+        };
+
+        match result {
+            Ok(Some(playing)) => {
+                debug!(target: AUDIO, "Playing sfx {} on channel {}", audio, playing);
+                self.sfx_channels.insert(
+                    playing,
+                    PlayingSfx {
+                        name: audio.to_string(),
+                        sound,
+                        started: Instant::now(),
+                        loop_started: None,
+                        volume,
+                        pan,
+                        priority,
+                    },
+                );
+                Some(playing)
+            }
+            Ok(None) => {
+                debug!(target: AUDIO, "sfx {} is silent", audio);
+                None
+            }
+            Err(err) => {
+                warn!(target: AUDIO, "Could not play sfx {}: {}", audio, err);
+                None
+            }
+        }
+    }
+
+    fn music(&mut self, synth: &SynthBank, audio: &str, fadems: u32, channelmask: u32) {
+        self.stop_music(fadems);
+        if audio == MUSIC_STOP {
+            return;
+        }
+        if let Err(err) = self.start_music(synth, audio, fadems, channelmask) {
+            warn!(target: AUDIO, "Could not play music {}: {}", audio, err);
+        }
+    }
+
+    fn update(&mut self, synth: &SynthBank) {
+        self.start_loops();
+        self.update_music(synth);
+    }
+
+    // Sets the mixer volumes of what's playing from the audio settings.
+    fn apply_settings(&mut self, settings: AudioSettings) {
+        self.settings = settings;
+        let music_volume = match &self.playing_music {
+            Some(playing) => settings.mix_volume(AudioBus::Music, self.sounds.music_volume(&playing.name)),
+            None => settings.mix_volume(AudioBus::Music, 1.0),
+        };
+        Music::set_volume(music_volume);
+        if let Some(PlayingMusic { channel: Some(channel), .. }) = self.playing_music {
+            Channel(channel).set_volume(music_volume);
+        }
+        for (channel, playing) in &self.sfx_channels {
+            Channel(*channel).set_volume(settings.mix_volume(AudioBus::Sfx, playing.volume));
+        }
+    }
+
+    fn set_sfx_mix(&mut self, channel: i32, volume: f32, pan: f32) {
+        if let Some(playing) = self.sfx_channels.get_mut(&channel) {
+            playing.volume = volume;
+            playing.pan = pan;
+        }
+        Channel(channel).set_volume(self.settings.mix_volume(AudioBus::Sfx, volume));
+        let (left, right) = mixer::panning(pan);
+        if let Err(err) = Channel(channel).set_panning(left, right) {
+            warn!(target: AUDIO, "Could not pan channel {}: {}", channel, err);
+        }
+    }
+
+    fn synth_changed(&mut self) {
+        self.synth_loops.clear();
+        self.synth_chunks.clear();
+    }
+
+    fn sfx_playing(&self, channel: i32) -> Option<&str> {
+        let playing = self.sfx_channels.get(&channel)?;
+        if !Channel(channel).is_playing() && !self.synth_loops.contains_key(&channel) {
+            return None;
+        }
+        Some(&playing.name)
+    }
+
+    fn sfx_note(&self, synth: &SynthBank, channel: i32) -> Option<usize> {
+        self.sfx_playing(channel)?;
+        let playing = self.sfx_channels.get(&channel)?;
+        match playing.sound? {
+            SynthSound::Sfx(n, offset, length) => {
+                let seconds = playing.started.elapsed().as_secs_f32();
+                synth::note_at(&synth.sfx[n], offset, length, seconds)
+            }
+            SynthSound::Music(_) => None,
+        }
+    }
+
+    fn music_playing(&self) -> Option<&str> {
+        self.playing_music.as_ref().map(|playing| playing.name.as_str())
+    }
+
+    fn sounds(&self) -> &SoundLibrary {
+        &self.sounds
+    }
+
+    fn sounds_mut(&mut self) -> &mut SoundLibrary {
+        &mut self.sounds
+    }
+}
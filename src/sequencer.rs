@@ -0,0 +1,210 @@
+use crate::p8::{P8Music, P8Sfx, MUSIC_COUNT};
+use crate::synth::{note_seconds, schedule, sfx_end, Rendered, Voice};
+
+/*
+A music sequencer for PICO-8 style patterns. A pattern plays up to 4 sfx at
+once, one per track, and songs are patterns played one after the other:
+
+    loop start   where playback jumps back to from a loop end
+    loop end     after this pattern go back to the nearest loop start at or
+                 before it, or to pattern 0 if there's none
+    stop         stop after this pattern
+
+Playback also stops before a pattern with every track off, or after the last
+pattern.
+
+A pattern lasts as long as its first track that doesn't loop, or the first
+track up to its loop end when they all do. Shorter tracks are silent for the
+rest of it and looping tracks repeat their loop.
+
+Songs render like looping sfx: an intro up to the end of the first pass through
+the song's loop, and the loop itself.
+*/
+
+// The pattern played after pattern p, None if the song ends.
+pub fn next_pattern(patterns: &[P8Music], p: usize) -> Option<usize> {
+    let pattern = patterns.get(p)?;
+    if pattern.stop {
+        return None;
+    }
+    let next = if pattern.loop_end {
+        (0..=p).rev().find(|i| patterns[*i].loop_start).unwrap_or(0)
+    } else {
+        p + 1
+    };
+    if next >= MUSIC_COUNT.min(patterns.len()) || patterns[next].is_empty() {
+        return None;
+    }
+    Some(next)
+}
+
+// The patterns a song starting at `start` plays in order, and which of them
+// loop forever after. The loop is empty when the song ends.
+pub fn song(patterns: &[P8Music], start: usize) -> (Vec<usize>, Vec<usize>) {
+    let mut order = Vec::new();
+    match patterns.get(start) {
+        Some(pattern) if !pattern.is_empty() => order.push(start),
+        _ => return (order, Vec::new()),
+    }
+    while let Some(next) = next_pattern(patterns, *order.last().unwrap()) {
+        if let Some(at) = order.iter().position(|p| *p == next) {
+            let looped = order[at..].to_vec();
+            return (order, looped);
+        }
+        order.push(next);
+    }
+    (order, Vec::new())
+}
+
+fn sfx_samples(sfx: &P8Sfx, notes: usize, sample_rate: u32) -> usize {
+    (notes as f64 * note_seconds(sfx.speed) as f64 * sample_rate as f64).round() as usize
+}
+
+// How many samples a pattern lasts.
+pub fn pattern_samples(bank: &[P8Sfx], pattern: &P8Music, sample_rate: u32) -> usize {
+    let tracks: Vec<&P8Sfx> = pattern
+        .channels
+        .iter()
+        .filter_map(|channel| channel.and_then(|n| bank.get(n as usize)))
+        .collect();
+    let leader = tracks
+        .iter()
+        .find(|sfx| !sfx_end(sfx).1)
+        .or_else(|| tracks.first());
+    match leader {
+        Some(sfx) => sfx_samples(sfx, sfx_end(sfx).0, sample_rate),
+        None => 0,
+    }
+}
+
+// Mixes the tracks of a pattern into `out`.
+pub fn render_pattern(bank: &[P8Sfx], pattern: &P8Music, sample_rate: u32, out: &mut Vec<f32>) {
+    let length = pattern_samples(bank, pattern, sample_rate);
+    let start = out.len();
+    out.resize(start + length, 0.0);
+
+    for n in pattern.channels.iter().flatten() {
+        let sfx = match bank.get(*n as usize) {
+            Some(sfx) => sfx,
+            None => continue,
+        };
+        // Enough notes to fill the pattern, following the track's loop.
+        let note_length = sfx_samples(sfx, 1, sample_rate).max(1);
+        let (notes, _) = schedule(sfx, 0, Some(length / note_length + 1));
+
+        let mut track = Vec::with_capacity(length + note_length);
+        Voice::new(sfx, bank, sample_rate).play(&notes, &mut track);
+        for (mixed, sample) in out[start..].iter_mut().zip(track) {
+            *mixed += sample;
+        }
+    }
+}
+
+// Renders the song that starts at pattern `start`.
+pub fn render_music(bank: &[P8Sfx], patterns: &[P8Music], start: usize, sample_rate: u32) -> Rendered {
+    let (order, looped) = song(patterns, start);
+    let mut rendered = Rendered::default();
+    for p in &order {
+        render_pattern(bank, &patterns[*p], sample_rate, &mut rendered.intro);
+    }
+    for p in &looped {
+        render_pattern(bank, &patterns[*p], sample_rate, &mut rendered.looped);
+    }
+    for sample in rendered.intro.iter_mut().chain(rendered.looped.iter_mut()) {
+        *sample = sample.clamp(-1.0, 1.0);
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p8::{P8Note, SFX_NOTES};
+
+    fn pattern(channels: [Option<u8>; 4], loop_start: bool, loop_end: bool, stop: bool) -> P8Music {
+        P8Music {
+            channels,
+            loop_start,
+            loop_end,
+            stop,
+        }
+    }
+
+    fn empty() -> P8Music {
+        pattern([None; 4], false, false, false)
+    }
+
+    fn tone(pitch: u8, speed: u8, loop_start: u8, loop_end: u8) -> P8Sfx {
+        P8Sfx {
+            notes: [P8Note {
+                pitch,
+                waveform: 3,
+                volume: 5,
+                effect: 0,
+                custom: false,
+            }; SFX_NOTES],
+            editor_mode: 0,
+            speed,
+            loop_start,
+            loop_end,
+        }
+    }
+
+    #[test]
+    fn songs_follow_the_flags() {
+        let on = [Some(0), None, None, None];
+        let mut patterns = vec![empty(); MUSIC_COUNT];
+        patterns[0] = pattern(on, false, false, false);
+        patterns[1] = pattern(on, false, false, false);
+        assert_eq!(song(&patterns, 0), (vec![0, 1], vec![]));
+        assert_eq!(song(&patterns, 2), (vec![], vec![]));
+
+        patterns[1].stop = true;
+        patterns[2] = pattern(on, false, false, false);
+        assert_eq!(song(&patterns, 0), (vec![0, 1], vec![]));
+
+        patterns[1] = pattern(on, true, false, false);
+        patterns[2].loop_end = true;
+        assert_eq!(song(&patterns, 0), (vec![0, 1, 2], vec![1, 2]));
+        assert_eq!(song(&patterns, 2), (vec![2, 1], vec![2, 1]));
+
+        // Without a loop start the loop goes back to pattern 0.
+        patterns[1].loop_start = false;
+        assert_eq!(song(&patterns, 1), (vec![1, 2, 0], vec![1, 2, 0]));
+    }
+
+    #[test]
+    fn patterns_last_as_long_as_the_first_track_that_doesnt_loop() {
+        let bank = vec![tone(24, 4, 0, 8), tone(30, 2, 0, 0), tone(36, 8, 4, 0)];
+        let note = |speed: usize| speed * 183;
+
+        let looping_first = pattern([Some(0), Some(1), None, None], false, false, false);
+        assert_eq!(pattern_samples(&bank, &looping_first, 22050), 32 * note(2));
+
+        let short = pattern([None, Some(2), Some(1), None], false, false, false);
+        assert_eq!(pattern_samples(&bank, &short, 22050), 4 * note(8));
+
+        let only_loops = pattern([Some(0), None, None, None], false, false, false);
+        assert_eq!(pattern_samples(&bank, &only_loops, 22050), 8 * note(4));
+
+        assert_eq!(pattern_samples(&bank, &empty(), 22050), 0);
+    }
+
+    #[test]
+    fn renders_mixed_tracks() {
+        let bank = vec![tone(24, 1, 0, 0), tone(36, 1, 0, 0)];
+        let mut patterns = vec![empty(); MUSIC_COUNT];
+        patterns[0] = pattern([Some(0), None, None, None], false, false, false);
+        patterns[1] = pattern([Some(0), Some(1), None, None], false, true, false);
+
+        let rendered = render_music(&bank, &patterns, 0, 22050);
+        assert_eq!(rendered.intro.len(), 2 * 32 * 183);
+        assert_eq!(rendered.looped.len(), 2 * 32 * 183);
+        assert_eq!(rendered, render_music(&bank, &patterns, 0, 22050));
+
+        let energy = |samples: &[f32]| samples.iter().map(|s| s * s).sum::<f32>();
+        let (first, second) = rendered.intro.split_at(32 * 183);
+        assert!(energy(second) > energy(first) * 1.5);
+        assert!(rendered.intro.iter().all(|s| s.abs() <= 1.0));
+    }
+}
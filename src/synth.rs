@@ -0,0 +1,488 @@
+use std::f32::consts::PI;
+
+use crate::p8::{P8Note, P8Sfx, SFX_NOTES};
+
+/*
+A PICO-8 style synthesizer. Sound effects are 32 notes played one after the
+other, each with a pitch, one of 8 waveforms, a volume and an effect.
+
+    pitch      0..63, C-0 upwards, A-2 (33) is 440Hz
+    waveform   triangle, tilted saw, saw, square, pulse, organ, noise, phaser
+    volume     0..7, 0 is silent
+    effect     none, slide, vibrato, drop, fade in, fade out, arp fast,
+               arp slow
+    speed      each note lasts speed ticks of 183 samples at 22050Hz
+
+When a note's custom bit is set its waveform picks sfx 0..7 instead, which is
+played as an instrument transposed by the note's pitch relative to C-2.
+
+A sound that loops renders as an intro (everything up to the end of the first
+pass through the loop) and the loop itself, to be repeated after it. Output is
+mono, -1.0..1.0 and the same for the same input every time, noise included.
+*/
+
+pub const WAVEFORMS: usize = 8;
+
+// A note lasts speed * TICK_SAMPLES samples at TICK_RATE.
+const TICK_SAMPLES: f32 = 183.0;
+const TICK_RATE: f32 = 22050.0;
+
+// Headroom for mixing several channels.
+const CHANNEL_GAIN: f32 = 0.35;
+
+// How many seconds a change of volume takes, to avoid clicks between notes.
+const RAMP_SECONDS: f32 = 0.002;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rendered {
+    pub intro: Vec<f32>,
+    // Empty unless the sound loops.
+    pub looped: Vec<f32>,
+}
+
+pub fn pitch_frequency(pitch: f32) -> f32 {
+    440.0 * 2f32.powf((pitch - 33.0) / 12.0)
+}
+
+// Seconds a note of the sfx lasts.
+pub fn note_seconds(speed: u8) -> f32 {
+    speed.max(1) as f32 * TICK_SAMPLES / TICK_RATE
+}
+
+// The value of waveform 0..5 and 7 at phase t (0..1). Noise is done by the
+// voice since it needs state.
+fn waveform(waveform: u8, t: f32, t2: f32) -> f32 {
+    let triangle = |t: f32| 1.0 - 4.0 * (t - 0.5).abs();
+    match waveform {
+        0 => triangle(t),
+        1 => {
+            if t < 0.875 {
+                t / 0.875 * 2.0 - 1.0
+            } else {
+                (1.0 - t) / 0.125 * 2.0 - 1.0
+            }
+        }
+        2 => (t * 2.0 - 1.0) * 0.7,
+        3 => {
+            if t < 0.5 {
+                0.5
+            } else {
+                -0.5
+            }
+        }
+        4 => {
+            if t < 0.3125 {
+                0.5
+            } else {
+                -0.5
+            }
+        }
+        5 => {
+            let v = if t < 0.5 { 3.0 - (24.0 * t - 6.0).abs() } else { 1.0 - (16.0 * t - 12.0).abs() };
+            v / 3.0
+        }
+        _ => (triangle(t) + triangle(t2)) * 0.5,
+    }
+}
+
+// The note an sfx ends at (or loops back from) and whether it loops.
+pub fn sfx_end(sfx: &P8Sfx) -> (usize, bool) {
+    let (loop_start, loop_end) = (sfx.loop_start as usize, sfx.loop_end as usize);
+    let looping = loop_end > loop_start && loop_end <= SFX_NOTES;
+    // A loop end of 0 with a loop start makes the sfx that many notes long.
+    if !looping && loop_end == 0 && loop_start > 0 {
+        (loop_start.min(SFX_NOTES), false)
+    } else if looping {
+        (loop_end, true)
+    } else {
+        (SFX_NOTES, false)
+    }
+}
+
+// Which notes play and in what order, for the whole sound or a part of it.
+// A loop is only used when the sfx loops and no length was asked for.
+pub fn schedule(sfx: &P8Sfx, offset: usize, length: Option<usize>) -> (Vec<usize>, Vec<usize>) {
+    let (loop_start, loop_end) = (sfx.loop_start as usize, sfx.loop_end as usize);
+    let (end, looping) = sfx_end(sfx);
+
+    let mut intro = Vec::new();
+    let mut note = offset;
+    if let Some(length) = length {
+        while intro.len() < length && note < end {
+            intro.push(note);
+            note += 1;
+            if looping && note == loop_end {
+                note = loop_start;
+            }
+        }
+        return (intro, Vec::new());
+    }
+
+    intro.extend(offset..end);
+    if !looping {
+        // Trailing silent notes only make the sound longer.
+        while let Some(&last) = intro.last() {
+            if sfx.notes[last].volume != 0 {
+                break;
+            }
+            intro.pop();
+        }
+        return (intro, Vec::new());
+    }
+    (intro, (loop_start..loop_end).collect())
+}
+
+// The note playing `seconds` after the sfx started, None once it has ended.
+pub fn note_at(sfx: &P8Sfx, offset: usize, length: Option<usize>, seconds: f32) -> Option<usize> {
+    let (intro, looped) = schedule(sfx, offset, length);
+    let played = (seconds / note_seconds(sfx.speed)) as usize;
+    if played < intro.len() {
+        return Some(intro[played]);
+    }
+    if looped.is_empty() {
+        return None;
+    }
+    Some(looped[(played - intro.len()) % looped.len()])
+}
+
+// Plays notes of an sfx into samples.
+pub struct Voice<'b> {
+    // The sfx the notes come from and the bank custom instruments come from.
+    sfx: &'b P8Sfx,
+    bank: &'b [P8Sfx],
+    sample_rate: f32,
+    volume: f32,
+    phase: f32,
+    phase2: f32,
+    noise: f32,
+    noise_seed: u32,
+    noise_phase: f32,
+    amp: f32,
+    // Samples played since the voice started, for arpeggios and vibrato.
+    time: u64,
+    previous: Option<P8Note>,
+}
+
+impl<'b> Voice<'b> {
+    pub fn new(sfx: &'b P8Sfx, bank: &'b [P8Sfx], sample_rate: u32) -> Voice<'b> {
+        Voice {
+            sfx,
+            bank,
+            sample_rate: sample_rate as f32,
+            volume: 1.0,
+            phase: 0.0,
+            phase2: 0.0,
+            noise: 0.0,
+            noise_seed: 0x2545_f491,
+            noise_phase: 0.0,
+            amp: 0.0,
+            time: 0,
+            previous: None,
+        }
+    }
+
+    // Samples a note lasts, counting from the start of the sound so rounding
+    // doesn't build up.
+    fn note_end(&self, notes_played: usize) -> u64 {
+        (notes_played as f64 * note_seconds(self.sfx.speed) as f64 * self.sample_rate as f64).round() as u64
+    }
+
+    fn next_noise(&mut self) -> f32 {
+        // xorshift32, so noise is the same every time.
+        let mut x = self.noise_seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_seed = x;
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+
+    // Plays the notes in order and appends the samples to `out`.
+    pub fn play(&mut self, notes: &[usize], out: &mut Vec<f32>) {
+        let start = self.time;
+        let start_notes = (start as f64 / (note_seconds(self.sfx.speed) as f64 * self.sample_rate as f64)).round() as usize;
+        for (i, &index) in notes.iter().enumerate() {
+            let note = self.sfx.notes[index];
+            let begin = self.note_end(start_notes + i);
+            let end = self.note_end(start_notes + i + 1);
+            let length = (end - begin).max(1);
+            for s in 0..length {
+                let progress = s as f32 / length as f32;
+                let sample = self.sample(&note, index, progress, s);
+                out.push(sample);
+                self.time += 1;
+            }
+            self.previous = Some(note);
+        }
+    }
+
+    // One sample of a note, `progress` is how far through the note (0..1)
+    // and `s` the sample within it.
+    fn sample(&mut self, note: &P8Note, index: usize, progress: f32, s: u64) -> f32 {
+        let seconds = self.time as f32 / self.sample_rate;
+        let mut pitch = note.pitch as f32;
+        let mut volume = note.volume as f32 / 7.0;
+
+        match note.effect {
+            1 => {
+                // Slide from the previous note's pitch and volume.
+                if let Some(previous) = self.previous {
+                    let from_pitch = previous.pitch as f32;
+                    pitch = from_pitch + (pitch - from_pitch) * progress;
+                    let from_volume = previous.volume as f32 / 7.0;
+                    volume = from_volume + (volume - from_volume) * progress;
+                }
+            }
+            2 => pitch += 0.25 * (2.0 * PI * 7.5 * seconds).sin(),
+            4 => volume *= progress,
+            5 => volume *= 1.0 - progress,
+            6 | 7 => {
+                // Cycles through the group of 4 notes this one is in.
+                let ticks = if note.effect == 6 { 4.0 } else { 8.0 };
+                let ticks = if self.sfx.speed <= 8 { ticks / 2.0 } else { ticks };
+                let step = (seconds * TICK_RATE / TICK_SAMPLES / ticks) as usize;
+                let group = index & !3;
+                let arp = self.sfx.notes[(group + step % 4).min(SFX_NOTES - 1)];
+                pitch = arp.pitch as f32;
+            }
+            _ => {}
+        }
+
+        let mut frequency = pitch_frequency(pitch);
+        if note.effect == 3 {
+            frequency *= 1.0 - progress;
+        }
+
+        let value = if note.custom {
+            self.instrument(note, pitch, s)
+        } else {
+            self.oscillate(note.waveform, frequency)
+        };
+
+        // Ease towards the target volume.
+        let target = volume * self.volume;
+        let step = 1.0 / (RAMP_SECONDS * self.sample_rate).max(1.0);
+        if (target - self.amp).abs() <= step {
+            self.amp = target;
+        } else if target > self.amp {
+            self.amp += step;
+        } else {
+            self.amp -= step;
+        }
+        value * self.amp * CHANNEL_GAIN
+    }
+
+    fn oscillate(&mut self, wave: u8, frequency: f32) -> f32 {
+        let delta = frequency / self.sample_rate;
+        self.phase = (self.phase + delta).fract();
+        self.phase2 = (self.phase2 + delta * 109.0 / 110.0).fract();
+
+        if wave == 6 {
+            // Noise picks a new value more often at higher pitches.
+            self.noise_phase += delta * 2.0;
+            if self.noise_phase >= 1.0 {
+                self.noise_phase = self.noise_phase.fract();
+                let next = self.next_noise();
+                // Smoothed a little so low notes rumble rather than crackle.
+                self.noise = self.noise * 0.5 + next * 0.5;
+            }
+            return self.noise;
+        }
+        waveform(wave, self.phase, self.phase2)
+    }
+
+    // A custom instrument plays sfx 0..7 from the start of each note,
+    // transposed by the note's pitch against C-2 and scaled by its volume.
+    fn instrument(&mut self, note: &P8Note, pitch: f32, s: u64) -> f32 {
+        let instrument = match self.bank.get(note.waveform as usize) {
+            Some(instrument) => instrument,
+            None => return 0.0,
+        };
+        let note_samples = (note_seconds(instrument.speed) * self.sample_rate).max(1.0) as u64;
+        let (intro, looped) = schedule(instrument, 0, None);
+        let mut played = (s / note_samples) as usize;
+        if played >= intro.len() {
+            if looped.is_empty() {
+                return 0.0;
+            }
+            played = intro.len() - looped.len() + (played - intro.len()) % looped.len();
+        }
+        let inner = instrument.notes[intro.get(played).copied().unwrap_or(0)];
+        let frequency = pitch_frequency(inner.pitch as f32 + pitch - 24.0);
+        self.oscillate(inner.waveform, frequency) * inner.volume as f32 / 7.0
+    }
+}
+
+// Renders sfx n of the bank. `offset` is the first note and `length` how many
+// notes to play, all of them (and the loop) when None.
+pub fn render_sfx(bank: &[P8Sfx], n: usize, sample_rate: u32, offset: usize, length: Option<usize>) -> Rendered {
+    let sfx = match bank.get(n) {
+        Some(sfx) => sfx,
+        None => return Rendered::default(),
+    };
+    let (intro, looped) = schedule(sfx, offset, length);
+
+    let mut rendered = Rendered::default();
+    let mut voice = Voice::new(sfx, bank, sample_rate);
+    voice.play(&intro, &mut rendered.intro);
+    if !looped.is_empty() {
+        voice.play(&looped, &mut rendered.looped);
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(pitch: u8, waveform: u8, volume: u8, effect: u8) -> P8Note {
+        P8Note {
+            pitch,
+            waveform,
+            volume,
+            effect,
+            custom: false,
+        }
+    }
+
+    fn sfx(notes: &[P8Note], speed: u8, loop_start: u8, loop_end: u8) -> P8Sfx {
+        let mut all = [note(0, 0, 0, 0); SFX_NOTES];
+        all[..notes.len()].copy_from_slice(notes);
+        P8Sfx {
+            notes: all,
+            editor_mode: 0,
+            speed,
+            loop_start,
+            loop_end,
+        }
+    }
+
+    // Counts upward zero crossings, to measure pitch.
+    fn crossings(samples: &[f32]) -> usize {
+        samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count()
+    }
+
+    #[test]
+    fn pitches() {
+        assert!((pitch_frequency(33.0) - 440.0).abs() < 0.01);
+        assert!((pitch_frequency(45.0) - 880.0).abs() < 0.01);
+        assert!((note_seconds(1) - 183.0 / 22050.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn schedules() {
+        let plain = sfx(&[note(24, 0, 5, 0); 4], 8, 0, 0);
+        assert_eq!(schedule(&plain, 0, None), (vec![0, 1, 2, 3], vec![]));
+        assert_eq!(schedule(&plain, 2, None), (vec![2, 3], vec![]));
+        assert_eq!(schedule(&plain, 1, Some(2)), (vec![1, 2], vec![]));
+
+        let short = sfx(&[note(24, 0, 5, 0); 8], 8, 3, 0);
+        assert_eq!(schedule(&short, 0, None), (vec![0, 1, 2], vec![]));
+
+        let looping = sfx(&[note(24, 0, 5, 0); 8], 8, 2, 4);
+        assert_eq!(schedule(&looping, 0, None), (vec![0, 1, 2, 3], vec![2, 3]));
+        assert_eq!(schedule(&looping, 0, Some(7)), (vec![0, 1, 2, 3, 2, 3, 2], vec![]));
+    }
+
+    #[test]
+    fn notes_over_time() {
+        let looping = sfx(&[note(24, 0, 5, 0); 8], 8, 2, 4);
+        let note = note_seconds(8);
+        assert_eq!(note_at(&looping, 0, None, 0.0), Some(0));
+        assert_eq!(note_at(&looping, 0, None, note * 3.5), Some(3));
+        assert_eq!(note_at(&looping, 0, None, note * 4.5), Some(2));
+        assert_eq!(note_at(&looping, 0, None, note * 7.5), Some(3));
+        assert_eq!(note_at(&looping, 1, Some(2), note * 1.5), Some(2));
+        assert_eq!(note_at(&looping, 1, Some(2), note * 2.5), None);
+    }
+
+    #[test]
+    fn renders_notes_at_pitch_and_length() {
+        let bank = vec![sfx(&[note(33, 3, 7, 0); 4], 30, 0, 0)];
+        let rendered = render_sfx(&bank, 0, 22050, 0, None);
+        assert_eq!(rendered.intro.len(), 4 * 30 * 183);
+        assert!(rendered.looped.is_empty());
+
+        // About 440 cycles a second.
+        let seconds = rendered.intro.len() as f32 / 22050.0;
+        let hz = crossings(&rendered.intro) as f32 / seconds;
+        assert!((hz - 440.0).abs() < 5.0, "{}", hz);
+        assert!(rendered.intro.iter().all(|s| s.abs() <= 1.0));
+    }
+
+    #[test]
+    fn every_waveform_makes_sound() {
+        for wave in 0..WAVEFORMS as u8 {
+            let bank = vec![sfx(&[note(30, wave, 7, 0)], 16, 0, 0)];
+            let rendered = render_sfx(&bank, 0, 22050, 0, None);
+            let peak = rendered.intro.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+            assert!(peak > 0.05, "waveform {} peak {}", wave, peak);
+        }
+    }
+
+    #[test]
+    fn effects_shape_the_note() {
+        let loudness = |samples: &[f32]| samples.iter().map(|s| s.abs()).sum::<f32>();
+
+        let fade_out = vec![sfx(&[note(30, 3, 7, 5)], 32, 0, 0)];
+        let samples = render_sfx(&fade_out, 0, 22050, 0, None).intro;
+        let half = samples.len() / 2;
+        assert!(loudness(&samples[..half]) > loudness(&samples[half..]) * 2.0);
+
+        let fade_in = vec![sfx(&[note(30, 3, 7, 4)], 32, 0, 0)];
+        let samples = render_sfx(&fade_in, 0, 22050, 0, None).intro;
+        assert!(loudness(&samples[..half]) * 2.0 < loudness(&samples[half..]));
+
+        let drop = vec![sfx(&[note(40, 3, 7, 3)], 32, 0, 0)];
+        let samples = render_sfx(&drop, 0, 22050, 0, None).intro;
+        assert!(crossings(&samples[..half]) > crossings(&samples[half..]) * 2);
+
+        let slide = vec![sfx(&[note(20, 3, 7, 0), note(44, 3, 7, 1)], 32, 0, 0)];
+        let samples = render_sfx(&slide, 0, 22050, 0, None).intro;
+        let second = &samples[samples.len() / 2..];
+        let quarter = second.len() / 4;
+        assert!(crossings(&second[..quarter]) * 2 < crossings(&second[3 * quarter..]));
+    }
+
+    #[test]
+    fn arpeggios_step_through_the_group() {
+        let notes = [note(24, 3, 7, 6), note(28, 3, 7, 6), note(31, 3, 7, 6), note(36, 3, 7, 6)];
+        let arp = vec![sfx(&notes, 32, 0, 0)];
+        let plain = vec![sfx(&[note(24, 3, 7, 0); 4], 32, 0, 0)];
+        let arp_samples = render_sfx(&arp, 0, 22050, 0, None).intro;
+        let plain_samples = render_sfx(&plain, 0, 22050, 0, None).intro;
+        assert!(crossings(&arp_samples) > crossings(&plain_samples));
+    }
+
+    #[test]
+    fn loops_render_separately() {
+        let bank = vec![sfx(&[note(30, 0, 5, 0); 8], 4, 4, 8)];
+        let rendered = render_sfx(&bank, 0, 22050, 0, None);
+        let note = 4 * 183;
+        assert_eq!(rendered.intro.len(), 8 * note);
+        assert_eq!(rendered.looped.len(), 4 * note);
+    }
+
+    #[test]
+    fn custom_instruments_and_silence() {
+        let mut lead = sfx(&[note(36, 1, 7, 0)], 16, 0, 0);
+        lead.notes[0].custom = true;
+        let bank = vec![lead.clone(), sfx(&[note(24, 3, 7, 0); 32], 1, 0, 0), lead];
+        // sfx 2 plays sfx 1 as an instrument, an octave up.
+        let samples = render_sfx(&bank, 2, 22050, 0, None).intro;
+        let plain = render_sfx(&[sfx(&[note(36, 3, 7, 0)], 16, 0, 0)], 0, 22050, 0, None).intro;
+        assert_eq!(samples.len(), plain.len());
+        let diff = (crossings(&samples) as i32 - crossings(&plain) as i32).abs();
+        assert!(diff <= 2, "{} vs {}", crossings(&samples), crossings(&plain));
+
+        let silent = vec![sfx(&[], 16, 0, 0)];
+        assert!(render_sfx(&silent, 0, 22050, 0, None).intro.is_empty());
+        assert!(render_sfx(&silent, 5, 22050, 0, None).intro.is_empty());
+    }
+
+    #[test]
+    fn rendering_is_deterministic() {
+        let bank = vec![sfx(&[note(30, 6, 7, 0), note(20, 6, 5, 2)], 8, 0, 0)];
+        assert_eq!(render_sfx(&bank, 0, 44100, 0, None), render_sfx(&bank, 0, 44100, 0, None));
+    }
+}
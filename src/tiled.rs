@@ -0,0 +1,750 @@
+use base64::Engine;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde_json::Value;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::Read;
+
+use crate::tilemap::TileFrame;
+
+/*
+Maps made with the Tiled editor (https://www.mapeditor.org), either .tmx (XML)
+or .tmj (JSON), with inline or external tilesets.
+
+Tile layers become map layers in the order they appear. A Tiled GID is turned
+into a sprite index by taking the tile's column and row in its tileset and
+finding the same column and row on the 16 x 16 spritesheet, so a tileset made
+from sprites.png maps one to one. Object layers are kept as a list of
+TiledObjects for the game to query. Tile animations set up in a tileset become
+animated tiles on the matching sprites.
+*/
+
+// The top bits of a GID flag flipped and rotated tiles.
+const GID_FLAGS: u32 = 0xf000_0000;
+
+pub type LoadFn<'a> = dyn Fn(&str) -> Result<Vec<u8>, Box<dyn Error>> + 'a;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TiledProperty {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TiledObject {
+    pub id: u32,
+    pub name: String,
+    // The object's type (class in newer versions of Tiled).
+    pub kind: String,
+    // The name of the object layer it came from.
+    pub layer: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    // Set for tile objects.
+    pub gid: Option<u32>,
+    pub properties: HashMap<String, TiledProperty>,
+}
+
+impl TiledObject {
+    pub fn property(&self, name: &str) -> Option<&TiledProperty> {
+        self.properties.get(name)
+    }
+
+    // Whether the point (x, y) is inside the object's rectangle.
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TiledFrame {
+    // Local to the tileset.
+    pub tile_id: u32,
+    // In milliseconds.
+    pub duration: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct TiledTileset {
+    pub name: String,
+    pub first_gid: u32,
+    pub columns: u32,
+    pub tile_count: u32,
+    // Animated tiles by local tile id.
+    pub animations: Vec<(u32, Vec<TiledFrame>)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TiledLayer {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    // Row-major GIDs, 0 is an empty cell.
+    pub gids: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TiledMap {
+    pub width: u32,
+    pub height: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub tilesets: Vec<TiledTileset>,
+    pub layers: Vec<TiledLayer>,
+    pub objects: Vec<TiledObject>,
+}
+
+impl TiledMap {
+    // Loads a .tmx or .tmj map. `load` reads files relative to the asset root
+    // and is used for the map itself and for external tilesets.
+    pub fn load(name: &str, load: &LoadFn) -> Result<TiledMap, Box<dyn Error>> {
+        let bytes = load(name)?;
+        let text = String::from_utf8(bytes)?;
+        let load_sibling = |source: &str| load(&sibling_path(name, source));
+
+        if name.ends_with(".tmx") {
+            TiledMap::from_tmx(&text, &load_sibling)
+        } else {
+            TiledMap::from_tmj(&text, &load_sibling)
+        }
+        .map_err(|e| format!("{}: {}", name, e).into())
+    }
+
+    // Parses a .tmx map, `load` reads external tilesets relative to the map.
+    pub fn from_tmx(text: &str, load: &LoadFn) -> Result<TiledMap, Box<dyn Error>> {
+        let doc = roxmltree::Document::parse(text)?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "map" {
+            return Err("Expected a <map> element".into());
+        }
+        if xml_attr(&root, "infinite")? == Some(1) {
+            return Err("Infinite maps are not supported".into());
+        }
+
+        let mut map = TiledMap {
+            width: xml_attr(&root, "width")?.unwrap_or(0),
+            height: xml_attr(&root, "height")?.unwrap_or(0),
+            tile_width: xml_attr(&root, "tilewidth")?.unwrap_or(8),
+            tile_height: xml_attr(&root, "tileheight")?.unwrap_or(8),
+            tilesets: Vec::new(),
+            layers: Vec::new(),
+            objects: Vec::new(),
+        };
+
+        for node in root.children().filter(|n| n.has_tag_name("tileset")) {
+            let first_gid = xml_attr(&node, "firstgid")?.unwrap_or(1);
+            let tileset = match node.attribute("source") {
+                Some(source) => {
+                    let bytes = load(source)?;
+                    let text = String::from_utf8(bytes)?;
+                    if source.ends_with(".tsx") {
+                        let doc = roxmltree::Document::parse(&text)?;
+                        parse_tsx(&doc.root_element(), first_gid)?
+                    } else {
+                        parse_tsj(&serde_json::from_str(&text)?, first_gid)
+                    }
+                }
+                None => parse_tsx(&node, first_gid)?,
+            };
+            map.tilesets.push(tileset);
+        }
+
+        map.parse_tmx_layers(&root, "")?;
+        Ok(map)
+    }
+
+    fn parse_tmx_layers(&mut self, parent: &roxmltree::Node, group: &str) -> Result<(), Box<dyn Error>> {
+        for node in parent.children().filter(|n| n.is_element()) {
+            let name = join_group(group, node.attribute("name").unwrap_or(""));
+            match node.tag_name().name() {
+                "layer" => {
+                    let width = xml_attr(&node, "width")?.unwrap_or(self.width);
+                    let height = xml_attr(&node, "height")?.unwrap_or(self.height);
+                    let data = node
+                        .children()
+                        .find(|n| n.has_tag_name("data"))
+                        .ok_or_else(|| format!("Layer {} has no data", name))?;
+                    let gids = parse_tmx_data(&data)?;
+                    self.push_layer(name, width, height, gids)?;
+                }
+                "objectgroup" => {
+                    for object in node.children().filter(|n| n.has_tag_name("object")) {
+                        self.objects.push(parse_tmx_object(&object, &name)?);
+                    }
+                }
+                "group" => self.parse_tmx_layers(&node, &name)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Parses a .tmj map, `load` reads external tilesets relative to the map.
+    pub fn from_tmj(text: &str, load: &LoadFn) -> Result<TiledMap, Box<dyn Error>> {
+        let root: Value = serde_json::from_str(text)?;
+        if root["infinite"].as_bool() == Some(true) {
+            return Err("Infinite maps are not supported".into());
+        }
+
+        let mut map = TiledMap {
+            width: json_u32(&root, "width").unwrap_or(0),
+            height: json_u32(&root, "height").unwrap_or(0),
+            tile_width: json_u32(&root, "tilewidth").unwrap_or(8),
+            tile_height: json_u32(&root, "tileheight").unwrap_or(8),
+            tilesets: Vec::new(),
+            layers: Vec::new(),
+            objects: Vec::new(),
+        };
+
+        for tileset in root["tilesets"].as_array().into_iter().flatten() {
+            let first_gid = json_u32(tileset, "firstgid").unwrap_or(1);
+            let tileset = match tileset["source"].as_str() {
+                Some(source) => {
+                    let bytes = load(source)?;
+                    let text = String::from_utf8(bytes)?;
+                    if source.ends_with(".tsx") {
+                        let doc = roxmltree::Document::parse(&text)?;
+                        parse_tsx(&doc.root_element(), first_gid)?
+                    } else {
+                        parse_tsj(&serde_json::from_str(&text)?, first_gid)
+                    }
+                }
+                None => parse_tsj(tileset, first_gid),
+            };
+            map.tilesets.push(tileset);
+        }
+
+        map.parse_tmj_layers(&root["layers"], "")?;
+        Ok(map)
+    }
+
+    fn parse_tmj_layers(&mut self, layers: &Value, group: &str) -> Result<(), Box<dyn Error>> {
+        for layer in layers.as_array().into_iter().flatten() {
+            let name = join_group(group, layer["name"].as_str().unwrap_or(""));
+            match layer["type"].as_str() {
+                Some("tilelayer") => {
+                    let width = json_u32(layer, "width").unwrap_or(self.width);
+                    let height = json_u32(layer, "height").unwrap_or(self.height);
+                    let gids = parse_tmj_data(layer)?;
+                    self.push_layer(name, width, height, gids)?;
+                }
+                Some("objectgroup") => {
+                    for object in layer["objects"].as_array().into_iter().flatten() {
+                        self.objects.push(parse_tmj_object(object, &name)?);
+                    }
+                }
+                Some("group") => self.parse_tmj_layers(&layer["layers"], &name)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn push_layer(&mut self, name: String, width: u32, height: u32, gids: Vec<u32>) -> Result<(), Box<dyn Error>> {
+        let size = (width as usize)
+            .checked_mul(height as usize)
+            .ok_or_else(|| format!("Layer {} is too big at {}x{}", name, width, height))?;
+        if gids.len() != size {
+            return Err(format!(
+                "Layer {} has {} tiles, expected {}x{}",
+                name,
+                gids.len(),
+                width,
+                height
+            )
+            .into());
+        }
+        self.layers.push(TiledLayer {
+            name,
+            width,
+            height,
+            gids,
+        });
+        Ok(())
+    }
+
+    // Turns a GID into a sprite index, 0 for an empty cell. Flip flags are
+    // dropped since sprites in the map can't be flipped.
+    pub fn sprite(&self, gid: u32) -> Result<u8, Box<dyn Error>> {
+        let gid = gid & !GID_FLAGS;
+        if gid == 0 {
+            return Ok(0);
+        }
+
+        let tileset = self
+            .tilesets
+            .iter()
+            .filter(|t| t.first_gid <= gid)
+            .max_by_key(|t| t.first_gid)
+            .ok_or_else(|| format!("GID {} is not in any tileset", gid))?;
+
+        let local = gid - tileset.first_gid;
+        let columns = if tileset.columns == 0 { 16 } else { tileset.columns };
+        let (col, row) = (local % columns, local / columns);
+        if col >= 16 || row >= 16 {
+            return Err(format!(
+                "Tile {} of tileset {} is outside the 16x16 spritesheet",
+                local, tileset.name
+            )
+            .into());
+        }
+        Ok((row * 16 + col) as u8)
+    }
+
+    // The animated tiles of every tileset, as sprite animations.
+    pub fn tile_animations(&self) -> Result<BTreeMap<u8, Vec<TileFrame>>, Box<dyn Error>> {
+        let mut animations = BTreeMap::new();
+        for tileset in &self.tilesets {
+            for (tile_id, frames) in &tileset.animations {
+                let base = self.sprite(tileset.first_gid + tile_id)?;
+                let frames = frames
+                    .iter()
+                    .map(|frame| {
+                        Ok(TileFrame {
+                            sprite: self.sprite(tileset.first_gid + frame.tile_id)?,
+                            duration: frame.duration,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+                animations.insert(base, frames);
+            }
+        }
+        Ok(animations)
+    }
+}
+
+// Resolves a path relative to the file `name` is in.
+pub fn sibling_path(name: &str, rel: &str) -> String {
+    match name.rfind('/') {
+        Some(i) => format!("{}/{}", &name[..i], rel),
+        None => rel.to_string(),
+    }
+}
+
+fn join_group(group: &str, name: &str) -> String {
+    if group.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", group, name)
+    }
+}
+
+fn xml_attr<T: std::str::FromStr>(node: &roxmltree::Node, name: &str) -> Result<Option<T>, Box<dyn Error>> {
+    match node.attribute(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid {} '{}'", name, value).into()),
+        None => Ok(None),
+    }
+}
+
+fn json_u32(value: &Value, key: &str) -> Option<u32> {
+    value[key].as_u64().map(|v| v as u32)
+}
+
+fn json_f32(value: &Value, key: &str) -> f32 {
+    value[key].as_f64().unwrap_or(0.0) as f32
+}
+
+fn parse_tsx(node: &roxmltree::Node, first_gid: u32) -> Result<TiledTileset, Box<dyn Error>> {
+    let mut animations = Vec::new();
+    for tile in node.children().filter(|n| n.has_tag_name("tile")) {
+        let animation = match tile.children().find(|n| n.has_tag_name("animation")) {
+            Some(animation) => animation,
+            None => continue,
+        };
+        let mut frames = Vec::new();
+        for frame in animation.children().filter(|n| n.has_tag_name("frame")) {
+            frames.push(TiledFrame {
+                tile_id: xml_attr(&frame, "tileid")?.unwrap_or(0),
+                duration: xml_attr(&frame, "duration")?.unwrap_or(0),
+            });
+        }
+        animations.push((xml_attr(&tile, "id")?.unwrap_or(0), frames));
+    }
+
+    Ok(TiledTileset {
+        name: node.attribute("name").unwrap_or("").to_string(),
+        first_gid,
+        columns: xml_attr(node, "columns")?.unwrap_or(0),
+        tile_count: xml_attr(node, "tilecount")?.unwrap_or(0),
+        animations,
+    })
+}
+
+fn parse_tsj(value: &Value, first_gid: u32) -> TiledTileset {
+    let mut animations = Vec::new();
+    for tile in value["tiles"].as_array().into_iter().flatten() {
+        let frames: Vec<TiledFrame> = tile["animation"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|frame| TiledFrame {
+                tile_id: json_u32(frame, "tileid").unwrap_or(0),
+                duration: json_u32(frame, "duration").unwrap_or(0),
+            })
+            .collect();
+        if !frames.is_empty() {
+            animations.push((json_u32(tile, "id").unwrap_or(0), frames));
+        }
+    }
+
+    TiledTileset {
+        name: value["name"].as_str().unwrap_or("").to_string(),
+        first_gid,
+        columns: json_u32(value, "columns").unwrap_or(0),
+        tile_count: json_u32(value, "tilecount").unwrap_or(0),
+        animations,
+    }
+}
+
+// Decodes base64 tile data, optionally zlib or gzip compressed, into GIDs.
+fn decode_base64_gids(text: &str, compression: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(text.trim())?;
+    let bytes = match compression {
+        "" => bytes,
+        "zlib" => {
+            let mut out = Vec::new();
+            ZlibDecoder::new(&bytes[..]).read_to_end(&mut out)?;
+            out
+        }
+        "gzip" => {
+            let mut out = Vec::new();
+            GzDecoder::new(&bytes[..]).read_to_end(&mut out)?;
+            out
+        }
+        other => return Err(format!("Unsupported tile compression {}", other).into()),
+    };
+    if bytes.len() % 4 != 0 {
+        return Err("Tile data is not a whole number of GIDs".into());
+    }
+    Ok(bytes
+        .chunks(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+fn parse_tmx_data(data: &roxmltree::Node) -> Result<Vec<u32>, Box<dyn Error>> {
+    let text = data.text().unwrap_or("");
+    match data.attribute("encoding") {
+        Some("csv") => text
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().map_err(|_| format!("Invalid GID '{}'", s).into()))
+            .collect(),
+        Some("base64") => decode_base64_gids(text, data.attribute("compression").unwrap_or("")),
+        Some(other) => Err(format!("Unsupported tile encoding {}", other).into()),
+        None => data
+            .children()
+            .filter(|n| n.has_tag_name("tile"))
+            .map(|n| Ok(xml_attr(&n, "gid")?.unwrap_or(0)))
+            .collect(),
+    }
+}
+
+fn parse_tmj_data(layer: &Value) -> Result<Vec<u32>, Box<dyn Error>> {
+    match &layer["data"] {
+        Value::Array(gids) => gids
+            .iter()
+            .map(|gid| gid.as_u64().map(|g| g as u32).ok_or_else(|| "Invalid GID".into()))
+            .collect(),
+        Value::String(text) => decode_base64_gids(text, layer["compression"].as_str().unwrap_or("")),
+        _ => Err("Tile layer has no data".into()),
+    }
+}
+
+fn parse_property(kind: &str, value: &str) -> TiledProperty {
+    match kind {
+        "bool" => TiledProperty::Bool(value == "true"),
+        "int" | "object" => value
+            .parse()
+            .map(TiledProperty::Int)
+            .unwrap_or_else(|_| TiledProperty::String(value.to_string())),
+        "float" => value
+            .parse()
+            .map(TiledProperty::Float)
+            .unwrap_or_else(|_| TiledProperty::String(value.to_string())),
+        _ => TiledProperty::String(value.to_string()),
+    }
+}
+
+fn parse_tmx_object(node: &roxmltree::Node, layer: &str) -> Result<TiledObject, Box<dyn Error>> {
+    let mut properties = HashMap::new();
+    if let Some(props) = node.children().find(|n| n.has_tag_name("properties")) {
+        for prop in props.children().filter(|n| n.has_tag_name("property")) {
+            let name = prop.attribute("name").unwrap_or("").to_string();
+            let value = prop.attribute("value").or_else(|| prop.text()).unwrap_or("");
+            properties.insert(name, parse_property(prop.attribute("type").unwrap_or("string"), value));
+        }
+    }
+
+    Ok(TiledObject {
+        id: xml_attr(node, "id")?.unwrap_or(0),
+        name: node.attribute("name").unwrap_or("").to_string(),
+        kind: node
+            .attribute("type")
+            .or_else(|| node.attribute("class"))
+            .unwrap_or("")
+            .to_string(),
+        layer: layer.to_string(),
+        x: xml_attr(node, "x")?.unwrap_or(0.0),
+        y: xml_attr(node, "y")?.unwrap_or(0.0),
+        width: xml_attr(node, "width")?.unwrap_or(0.0),
+        height: xml_attr(node, "height")?.unwrap_or(0.0),
+        gid: xml_attr(node, "gid")?,
+        properties,
+    })
+}
+
+fn parse_tmj_object(value: &Value, layer: &str) -> Result<TiledObject, Box<dyn Error>> {
+    let mut properties = HashMap::new();
+    for prop in value["properties"].as_array().into_iter().flatten() {
+        let name = prop["name"].as_str().unwrap_or("").to_string();
+        let property = match &prop["value"] {
+            Value::Bool(b) => TiledProperty::Bool(*b),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) if prop["type"].as_str() != Some("float") => TiledProperty::Int(i),
+                _ => TiledProperty::Float(n.as_f64().unwrap_or(0.0)),
+            },
+            Value::String(s) => parse_property(prop["type"].as_str().unwrap_or("string"), s),
+            other => return Err(format!("Unsupported value for property {}: {}", name, other).into()),
+        };
+        properties.insert(name, property);
+    }
+
+    let kind = match value["type"].as_str() {
+        Some(kind) if !kind.is_empty() => kind,
+        _ => value["class"].as_str().unwrap_or(""),
+    };
+
+    Ok(TiledObject {
+        id: json_u32(value, "id").unwrap_or(0),
+        name: value["name"].as_str().unwrap_or("").to_string(),
+        kind: kind.to_string(),
+        layer: layer.to_string(),
+        x: json_f32(value, "x"),
+        y: json_f32(value, "y"),
+        width: json_f32(value, "width"),
+        height: json_f32(value, "height"),
+        gid: json_u32(value, "gid"),
+        properties,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn no_files(name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        Err(format!("No file {}", name).into())
+    }
+
+    fn tmx(tilesets: &str, layers: &str) -> String {
+        format!(
+            r#"<?xml version="1.0"?>
+<map width="2" height="2" tilewidth="8" tileheight="8">
+{}
+{}
+</map>"#,
+            tilesets, layers
+        )
+    }
+
+    fn base64_gids(gids: &[u32], compression: &str) -> String {
+        let bytes: Vec<u8> = gids.iter().flat_map(|g| g.to_le_bytes()).collect();
+        let bytes = match compression {
+            "zlib" => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&bytes).unwrap();
+                encoder.finish().unwrap()
+            }
+            "gzip" => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&bytes).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => bytes,
+        };
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+
+    #[test]
+    fn reads_csv_and_base64_layers() {
+        let tileset = r#"<tileset firstgid="1" name="sprites" columns="16" tilecount="256"/>"#;
+        let gids = [1, 2, 0, 17];
+
+        let csv = tmx(tileset, r#"<layer name="ground" width="2" height="2"><data encoding="csv">1,2,
+0,17</data></layer>"#);
+        let map = TiledMap::from_tmx(&csv, &no_files).unwrap();
+        assert_eq!((map.width, map.height), (2, 2));
+        assert_eq!(map.layers[0].name, "ground");
+        assert_eq!(map.layers[0].gids, gids);
+
+        for compression in &["", "zlib", "gzip"] {
+            let layer = format!(
+                r#"<layer name="ground"><data encoding="base64" compression="{}">
+{}
+</data></layer>"#,
+                compression,
+                base64_gids(&gids, compression)
+            );
+            let map = TiledMap::from_tmx(&tmx(tileset, &layer), &no_files).unwrap();
+            assert_eq!(map.layers[0].gids, gids, "{}", compression);
+        }
+
+        let tmj = format!(
+            r#"{{"width": 2, "height": 2, "tilesets": [{{"firstgid": 1, "columns": 16}}],
+                "layers": [{{"type": "tilelayer", "name": "a", "data": [1, 2, 0, 17]}},
+                           {{"type": "tilelayer", "name": "b", "compression": "zlib", "data": "{}"}}]}}"#,
+            base64_gids(&gids, "zlib")
+        );
+        let map = TiledMap::from_tmj(&tmj, &no_files).unwrap();
+        assert_eq!(map.layers.len(), 2);
+        assert_eq!(map.layers[0].gids, gids);
+        assert_eq!(map.layers[1].gids, gids);
+    }
+
+    #[test]
+    fn loads_external_tilesets() {
+        let load = |name: &str| -> Result<Vec<u8>, Box<dyn Error>> {
+            match name {
+                "maps/level.tmx" => Ok(tmx(
+                    r#"<tileset firstgid="1" source="../tiles/props.tsx"/>
+<tileset firstgid="9" source="../tiles/walls.tsx"/>"#,
+                    r#"<layer name="ground"><data encoding="csv">1,9,10,12</data></layer>"#,
+                )
+                .into_bytes()),
+                "maps/../tiles/props.tsx" => Ok(br#"<tileset name="props" columns="8" tilecount="8">
+<tile id="1"><animation><frame tileid="1" duration="100"/><frame tileid="2" duration="150"/></animation></tile>
+</tileset>"#
+                    .to_vec()),
+                "maps/../tiles/walls.tsx" => Ok(br#"<tileset name="walls" columns="2" tilecount="4"/>"#.to_vec()),
+                other => no_files(other),
+            }
+        };
+        let map = TiledMap::load("maps/level.tmx", &load).unwrap();
+        assert_eq!(map.tilesets.len(), 2);
+        assert_eq!((map.tilesets[1].name.as_str(), map.tilesets[1].first_gid), ("walls", 9));
+
+        // GID 9 is the first tile of walls, and 12 is its second row.
+        let sprites: Vec<u8> = map.layers[0].gids.iter().map(|&g| map.sprite(g).unwrap()).collect();
+        assert_eq!(sprites, [0, 0, 1, 17]);
+
+        let animations = map.tile_animations().unwrap();
+        let frames = &animations[&1];
+        assert_eq!((frames[0].sprite, frames[0].duration), (1, 100));
+        assert_eq!((frames[1].sprite, frames[1].duration), (2, 150));
+
+        assert!(TiledMap::load("maps/missing.tmx", &load).is_err());
+    }
+
+    #[test]
+    fn sprites_ignore_flip_bits() {
+        let text = tmx(
+            r#"<tileset firstgid="1" name="sprites" columns="16" tilecount="256"/>"#,
+            r#"<layer name="ground"><data encoding="csv">0,1,18,257</data></layer>"#,
+        );
+        let map = TiledMap::from_tmx(&text, &no_files).unwrap();
+        assert_eq!(map.sprite(0).unwrap(), 0);
+        assert_eq!(map.sprite(18).unwrap(), 17);
+        assert_eq!(map.sprite(18 | 0x8000_0000).unwrap(), 17);
+        assert_eq!(map.sprite(18 | 0x4000_0000 | 0x2000_0000).unwrap(), 17);
+        // Only flip bits set is still an empty cell.
+        assert_eq!(map.sprite(0x8000_0000).unwrap(), 0);
+        assert!(map.sprite(257).is_err());
+    }
+
+    #[test]
+    fn reads_objects_and_properties() {
+        let text = tmx(
+            "",
+            r#"<group name="things">
+<objectgroup name="spawns">
+<object id="3" name="start" type="player" x="16" y="24.5" width="8" height="8">
+<properties>
+<property name="lives" type="int" value="3"/>
+<property name="speed" type="float" value="1.5"/>
+<property name="flying" type="bool" value="true"/>
+<property name="note">hello</property>
+</properties>
+</object>
+<object id="4" class="coin" x="0" y="0" gid="5"/>
+</objectgroup>
+</group>"#,
+        );
+        let map = TiledMap::from_tmx(&text, &no_files).unwrap();
+        assert_eq!(map.objects.len(), 2);
+
+        let start = &map.objects[0];
+        assert_eq!((start.id, start.name.as_str(), start.kind.as_str()), (3, "start", "player"));
+        assert_eq!(start.layer, "things/spawns");
+        assert_eq!((start.x, start.y), (16.0, 24.5));
+        assert!(start.contains(23.0, 30.0));
+        assert!(!start.contains(24.0, 30.0));
+        assert_eq!(start.property("lives"), Some(&TiledProperty::Int(3)));
+        assert_eq!(start.property("speed"), Some(&TiledProperty::Float(1.5)));
+        assert_eq!(start.property("flying"), Some(&TiledProperty::Bool(true)));
+        assert_eq!(start.property("note"), Some(&TiledProperty::String("hello".to_string())));
+        assert_eq!(map.objects[1].kind, "coin");
+        assert_eq!(map.objects[1].gid, Some(5));
+
+        let tmj = r#"{"width": 1, "height": 1, "layers": [{"type": "objectgroup", "name": "spawns", "objects": [
+            {"id": 1, "name": "door", "class": "exit", "x": 8, "y": 4, "width": 16, "height": 8,
+             "properties": [{"name": "to", "type": "string", "value": "cave"},
+                            {"name": "scale", "type": "float", "value": 2},
+                            {"name": "locked", "type": "bool", "value": false}]}]}]}"#;
+        let map = TiledMap::from_tmj(tmj, &no_files).unwrap();
+        let door = &map.objects[0];
+        assert_eq!((door.kind.as_str(), door.layer.as_str()), ("exit", "spawns"));
+        assert_eq!((door.x, door.y, door.width, door.height), (8.0, 4.0, 16.0, 8.0));
+        assert_eq!(door.property("to"), Some(&TiledProperty::String("cave".to_string())));
+        assert_eq!(door.property("scale"), Some(&TiledProperty::Float(2.0)));
+        assert_eq!(door.property("locked"), Some(&TiledProperty::Bool(false)));
+    }
+
+    #[test]
+    fn bad_maps_are_errors() {
+        let tileset = r#"<tileset firstgid="1" columns="16"/>"#;
+        let bad = [
+            "<map",
+            "<tileset/>",
+            r#"<map width="2" height="2" infinite="1"/>"#,
+            r#"<map width="x"/>"#,
+        ];
+        for text in &bad {
+            assert!(TiledMap::from_tmx(text, &no_files).is_err(), "{}", text);
+        }
+
+        let layers = [
+            // Too few tiles for the map.
+            r#"<layer name="a"><data encoding="csv">1,2,3</data></layer>"#,
+            r#"<layer name="a"><data encoding="csv">1,x,3,4</data></layer>"#,
+            r#"<layer name="a"><data encoding="base64">!!!</data></layer>"#,
+            r#"<layer name="a"><data encoding="base64">AQID</data></layer>"#,
+            r#"<layer name="a"><data encoding="base64" compression="zstd">AQAAAA==</data></layer>"#,
+            r#"<layer name="a"><data encoding="base64" compression="zlib">AQAAAA==</data></layer>"#,
+            r#"<layer name="a"/>"#,
+            // Sizes that don't fit in memory are an error, not a crash.
+            r#"<layer name="a" width="4294967295" height="4294967295"><data encoding="csv">1</data></layer>"#,
+        ];
+        for layer in &layers {
+            assert!(TiledMap::from_tmx(&tmx(tileset, layer), &no_files).is_err(), "{}", layer);
+        }
+        assert!(TiledMap::from_tmx(&tmx(r#"<tileset firstgid="1" source="gone.tsx"/>"#, ""), &no_files).is_err());
+
+        assert!(TiledMap::from_tmj("{", &no_files).is_err());
+        assert!(TiledMap::from_tmj(r#"{"infinite": true}"#, &no_files).is_err());
+        let tmj = r#"{"width": 2, "height": 1, "layers": [{"type": "tilelayer", "name": "a", "data": [1, "x"]}]}"#;
+        assert!(TiledMap::from_tmj(tmj, &no_files).is_err());
+    }
+}
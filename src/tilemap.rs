@@ -0,0 +1,626 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/*
+The native map format, .bbmap:
+
+    magic         "BBMAP"
+    version       u16
+    width         u32
+    height        u32
+    layer_count   u8
+    layer_count x {
+        name_len  u8
+        name      utf-8
+    }
+    layer_count x run-length encoded cells, row-major, each run is
+        count     LEB128 varint
+        sprite    u8
+
+All integers are little-endian. Runs never cross from one layer to the next.
+*/
+
+pub const MAP_MAGIC: &[u8; 5] = b"BBMAP";
+pub const MAP_VERSION: u16 = 1;
+pub const MAP_EXTENSION: &str = "bbmap";
+// The most cells a map can have over all its layers, 64 MiB of sprites.
+pub const MAP_MAX_CELLS: usize = 1 << 26;
+
+// How cell coordinates outside the map are treated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MapAddressing {
+    // Outside the map is empty, reads give 0 and writes are ignored.
+    #[default]
+    Bounded,
+    // The map repeats in every direction.
+    Wrap,
+    // Coordinates are clamped to the nearest edge cell.
+    Clamp,
+}
+
+// How a layer moves with the camera when it is drawn with map.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct LayerScroll {
+    // 1.0 moves with the camera, 0.5 at half speed for depth, 0.0 not at all.
+    pub factor_x: f32,
+    pub factor_y: f32,
+    // Added to the draw position, in pixels.
+    pub offset_x: f32,
+    pub offset_y: f32,
+    // Repeat the drawn region to fill the clip window.
+    pub wrap_x: bool,
+    pub wrap_y: bool,
+}
+
+impl Default for LayerScroll {
+    fn default() -> LayerScroll {
+        LayerScroll {
+            factor_x: 1.0,
+            factor_y: 1.0,
+            offset_x: 0.0,
+            offset_y: 0.0,
+            wrap_x: false,
+            wrap_y: false,
+        }
+    }
+}
+
+impl LayerScroll {
+    // Where a region drawn at (sx, sy) lands on screen with the camera at
+    // (camera_x, camera_y).
+    pub fn origin(&self, sx: f32, sy: f32, camera_x: f32, camera_y: f32) -> (f32, f32) {
+        (
+            sx + self.offset_x - camera_x * self.factor_x,
+            sy + self.offset_y - camera_y * self.factor_y,
+        )
+    }
+}
+
+// A region of a layer for map to draw: cells celx..celx+celw, cely..cely+celh,
+// with the top left one at screen position (sx, sy).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapDraw {
+    pub celx: i32,
+    pub cely: i32,
+    pub sx: f32,
+    pub sy: f32,
+    pub celw: u32,
+    pub celh: u32,
+    pub layer: u8,
+    // Parallax settings to use instead of the layer's.
+    pub scroll: Option<LayerScroll>,
+}
+
+impl Default for MapDraw {
+    // One screen's worth of layer 0 from the top left of the map.
+    fn default() -> MapDraw {
+        MapDraw {
+            celx: 0,
+            cely: 0,
+            sx: 0.0,
+            sy: 0.0,
+            celw: 16,
+            celh: 16,
+            layer: 0,
+            scroll: None,
+        }
+    }
+}
+
+// One frame of an animated tile.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TileFrame {
+    pub sprite: u8,
+    // How long the frame shows, in milliseconds.
+    pub duration: u32,
+}
+
+// Maps a base sprite to the frames it cycles through. Cells keep holding the
+// base sprite, the frame is picked when they are drawn.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TileAnimations {
+    pub table: BTreeMap<u8, Vec<TileFrame>>,
+}
+
+impl TileAnimations {
+    pub fn set(&mut self, base: u8, frames: Vec<TileFrame>) {
+        if frames.is_empty() {
+            self.table.remove(&base);
+        } else {
+            self.table.insert(base, frames);
+        }
+    }
+
+    pub fn is_animated(&self, sprite: u8) -> bool {
+        self.table.contains_key(&sprite)
+    }
+
+    // The sprite to draw for `sprite` `time_ms` milliseconds in.
+    pub fn resolve(&self, sprite: u8, time_ms: u64) -> u8 {
+        let frames = match self.table.get(&sprite) {
+            Some(frames) => frames,
+            None => return sprite,
+        };
+        let total: u64 = frames.iter().map(|f| u64::from(f.duration)).sum();
+        if total == 0 {
+            return frames[0].sprite;
+        }
+
+        let mut t = time_ms % total;
+        for frame in frames {
+            if t < u64::from(frame.duration) {
+                return frame.sprite;
+            }
+            t -= u64::from(frame.duration);
+        }
+        sprite
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileMap {
+    pub width: u32,
+    pub height: u32,
+    pub layer_names: Vec<String>,
+    // Layer after layer, each one row-major.
+    pub cells: Vec<u8>,
+}
+
+impl TileMap {
+    pub fn new(width: u32, height: u32, layers: usize) -> TileMap {
+        TileMap {
+            width,
+            height,
+            layer_names: (0..layers).map(default_layer_name).collect(),
+            cells: vec![0; width as usize * height as usize * layers],
+        }
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layer_names.len()
+    }
+
+    // The number of cells in a map of this size, or an error if it has no
+    // cells or too many.
+    pub fn check_size(width: u32, height: u32, layers: usize) -> Result<usize, String> {
+        if width == 0 || height == 0 || layers == 0 {
+            return Err(format!("A {}x{} map with {} layers has no cells", width, height, layers));
+        }
+        if layers > u8::MAX as usize {
+            return Err(format!("A map can have at most {} layers, not {}", u8::MAX, layers));
+        }
+        match (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|size| size.checked_mul(layers))
+        {
+            Some(cells) if cells <= MAP_MAX_CELLS => Ok(cells),
+            _ => Err(format!(
+                "A {}x{} map with {} layers is over the limit of {} cells",
+                width, height, layers, MAP_MAX_CELLS
+            )),
+        }
+    }
+
+    fn layer_size(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    fn index(&self, x: u32, y: u32, layer: usize) -> Option<usize> {
+        if x < self.width && y < self.height && layer < self.layer_count() {
+            Some(layer * self.layer_size() + y as usize * self.width as usize + x as usize)
+        } else {
+            None
+        }
+    }
+
+    // Maps a cell coordinate onto the map, None if it falls outside it.
+    pub fn resolve(&self, x: i32, y: i32, addressing: MapAddressing) -> Option<(u32, u32)> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+        let (w, h) = (self.width as i64, self.height as i64);
+        let (x, y) = (x as i64, y as i64);
+        match addressing {
+            MapAddressing::Bounded => {
+                if x >= 0 && x < w && y >= 0 && y < h {
+                    Some((x as u32, y as u32))
+                } else {
+                    None
+                }
+            }
+            MapAddressing::Wrap => Some((x.rem_euclid(w) as u32, y.rem_euclid(h) as u32)),
+            MapAddressing::Clamp => Some((x.max(0).min(w - 1) as u32, y.max(0).min(h - 1) as u32)),
+        }
+    }
+
+    // Reads a cell using the given addressing, None if it is outside the map.
+    pub fn sample(&self, x: i32, y: i32, layer: usize, addressing: MapAddressing) -> Option<u8> {
+        let (x, y) = self.resolve(x, y, addressing)?;
+        self.get(x, y, layer)
+    }
+
+    // The sprite at a cell, None outside the map.
+    pub fn get(&self, x: u32, y: u32, layer: usize) -> Option<u8> {
+        self.index(x, y, layer).map(|i| self.cells[i])
+    }
+
+    // Sets a cell, returning false if it is outside the map.
+    pub fn set(&mut self, x: u32, y: u32, layer: usize, sprite: u8) -> bool {
+        match self.index(x, y, layer) {
+            Some(i) => {
+                self.cells[i] = sprite;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        if self.layer_count() > u8::MAX as usize {
+            return Err(format!("{} layers is too many, at most 255 can be saved", self.layer_count()).into());
+        }
+
+        writer.write_all(MAP_MAGIC)?;
+        writer.write_all(&MAP_VERSION.to_le_bytes())?;
+        writer.write_all(&self.width.to_le_bytes())?;
+        writer.write_all(&self.height.to_le_bytes())?;
+        writer.write_all(&[self.layer_count() as u8])?;
+        for name in self.layer_names.iter() {
+            if name.len() > u8::MAX as usize {
+                return Err(format!("Layer name {} is too long", name).into());
+            }
+            writer.write_all(&[name.len() as u8])?;
+            writer.write_all(name.as_bytes())?;
+        }
+
+        if self.layer_size() == 0 {
+            return Ok(());
+        }
+        for layer in self.cells.chunks(self.layer_size()) {
+            let mut i = 0;
+            while i < layer.len() {
+                let sprite = layer[i];
+                let run = layer[i..].iter().take_while(|s| **s == sprite).count();
+                write_varint(writer, run as u64)?;
+                writer.write_all(&[sprite])?;
+                i += run;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<TileMap, Box<dyn Error>> {
+        let mut magic = [0; 5];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAP_MAGIC {
+            return Err("Not a bbmap file".into());
+        }
+
+        let version = u16::from_le_bytes(read_array(reader)?);
+        if version > MAP_VERSION {
+            return Err(format!(
+                "Map version {} is newer than the supported version {}",
+                version, MAP_VERSION
+            )
+            .into());
+        }
+
+        let width = u32::from_le_bytes(read_array(reader)?);
+        let height = u32::from_le_bytes(read_array(reader)?);
+        let [layer_count] = read_array(reader)?;
+        let cell_count = TileMap::check_size(width, height, layer_count as usize)?;
+
+        let mut layer_names = Vec::new();
+        for _ in 0..layer_count {
+            let [len] = read_array(reader)?;
+            let mut name = vec![0; len as usize];
+            reader.read_exact(&mut name)?;
+            layer_names.push(String::from_utf8(name)?);
+        }
+
+        let mut map = TileMap {
+            width,
+            height,
+            layer_names,
+            cells: Vec::with_capacity(cell_count),
+        };
+        let layer_size = map.layer_size();
+        for layer in 0..layer_count {
+            let mut filled = 0;
+            while filled < layer_size {
+                let run = read_varint(reader)? as usize;
+                let [sprite] = read_array(reader)?;
+                if run == 0 || run > layer_size - filled {
+                    return Err(format!("Layer {} has a bad run of {} cells", layer, run).into());
+                }
+                map.cells.resize(map.cells.len() + run, sprite);
+                filled += run;
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TileMap, Box<dyn Error>> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        TileMap::read(&mut BufReader::new(file))
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e).into())
+    }
+}
+
+// The cells 0..count of a row of 8 pixel cells starting at screen position
+// `origin` that can touch the screen span clip_start..clip_start + clip_len.
+// Sprites are drawn at truncated positions, so one extra cell is kept on each
+// side.
+pub fn visible_cells(origin: f32, clip_start: f32, clip_len: f32, count: u32) -> Range<u32> {
+    let first = ((clip_start - origin) / 8.0).floor() - 1.0;
+    let last = ((clip_start + clip_len - origin) / 8.0).ceil() + 1.0;
+    let first = first.max(0.0).min(count as f32) as u32;
+    let last = last.max(0.0).min(count as f32) as u32;
+    first..last.max(first)
+}
+
+// The screen positions a region `span` pixels long, drawn at `origin`, has to
+// be drawn at to fill clip_start..clip_start + clip_len when it wraps.
+pub fn wrap_origins(origin: f32, span: f32, wrap: bool, clip_start: f32, clip_len: f32) -> Vec<f32> {
+    if !wrap || span <= 0.0 {
+        return vec![origin];
+    }
+
+    let mut origins = Vec::new();
+    let mut start = clip_start - (clip_start - origin).rem_euclid(span);
+    while start < clip_start + clip_len {
+        origins.push(start);
+        start += span;
+    }
+    origins
+}
+
+pub fn default_layer_name(layer: usize) -> String {
+    format!("layer{}", layer)
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], Box<dyn Error>> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> Result<(), Box<dyn Error>> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            writer.write_all(&[byte])?;
+            return Ok(());
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> Result<u64, Box<dyn Error>> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let [byte] = read_array(reader)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("Run length is too long".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(map: &TileMap) -> TileMap {
+        let mut bytes = Vec::new();
+        map.write(&mut bytes).unwrap();
+        TileMap::read(&mut &bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn round_trips_empty_map() {
+        let map = TileMap::new(256, 256, 4);
+        assert_eq!(round_trip(&map), map);
+    }
+
+    #[test]
+    fn round_trips_layers_and_names() {
+        let mut map = TileMap::new(300, 7, 3);
+        map.layer_names = vec!["ground".to_string(), "".to_string(), "décor".to_string()];
+        for (i, cell) in map.cells.iter_mut().enumerate() {
+            // Long runs, short runs and single cells.
+            *cell = if i < 1000 { 48 } else { (i * 7 % 5) as u8 * 50 };
+        }
+        map.cells[2099] = 255;
+        assert_eq!(round_trip(&map), map);
+    }
+
+    #[test]
+    fn out_of_bounds_access_is_checked() {
+        let mut map = TileMap::new(1024, 32, 8);
+        assert!(map.set(1023, 31, 7, 5));
+        assert_eq!(map.get(1023, 31, 7), Some(5));
+        assert_eq!(map.get(1023, 31, 6), Some(0));
+
+        assert!(!map.set(1024, 0, 0, 5));
+        assert!(!map.set(0, 32, 0, 5));
+        assert!(!map.set(0, 0, 8, 5));
+        assert_eq!(map.get(1024, 0, 0), None);
+        assert_eq!(map.get(0, 32, 0), None);
+        assert_eq!(map.get(0, 0, 8), None);
+    }
+
+    #[test]
+    fn addressing_modes() {
+        let mut map = TileMap::new(4, 3, 1);
+        map.set(0, 0, 0, 1);
+        map.set(3, 2, 0, 2);
+
+        assert_eq!(map.sample(-1, -1, 0, MapAddressing::Bounded), None);
+        assert_eq!(map.sample(4, 0, 0, MapAddressing::Bounded), None);
+        assert_eq!(map.sample(3, 2, 0, MapAddressing::Bounded), Some(2));
+
+        assert_eq!(map.sample(-1, -1, 0, MapAddressing::Wrap), Some(2));
+        assert_eq!(map.sample(4, 3, 0, MapAddressing::Wrap), Some(1));
+        assert_eq!(map.sample(-8, 6, 0, MapAddressing::Wrap), Some(1));
+
+        assert_eq!(map.sample(-5, -5, 0, MapAddressing::Clamp), Some(1));
+        assert_eq!(map.sample(100, 100, 0, MapAddressing::Clamp), Some(2));
+        assert_eq!(map.resolve(i32::MIN, i32::MAX, MapAddressing::Clamp), Some((0, 2)));
+
+        // The layer is never wrapped.
+        assert_eq!(map.sample(0, 0, 1, MapAddressing::Wrap), None);
+        assert_eq!(TileMap::new(0, 0, 1).sample(0, 0, 0, MapAddressing::Wrap), None);
+    }
+
+    #[test]
+    fn visible_cells_cover_the_clip_window() {
+        // A 256 cell row drawn from the origin, seen through 128 pixels.
+        assert_eq!(visible_cells(0.0, 0.0, 128.0, 256), 0..17);
+        // Scrolled 1000 pixels right, cell 125 starts at 0.
+        assert_eq!(visible_cells(-1000.0, 0.0, 128.0, 256), 124..142);
+        // Entirely off screen in either direction.
+        assert_eq!(visible_cells(200.0, 0.0, 128.0, 256), 0..0);
+        assert_eq!(visible_cells(-4000.0, 0.0, 128.0, 256), 256..256);
+        // A small clip window.
+        assert_eq!(visible_cells(0.0, 40.0, 8.0, 256), 4..7);
+        assert_eq!(visible_cells(0.0, 0.0, 0.0, 0), 0..0);
+    }
+
+    #[test]
+    fn layer_scroll_moves_with_the_camera() {
+        let scroll = LayerScroll::default();
+        assert_eq!(scroll.origin(16.0, 0.0, 100.0, 40.0), (-84.0, -40.0));
+
+        let scroll = LayerScroll {
+            factor_x: 0.5,
+            factor_y: 0.0,
+            offset_x: 4.0,
+            offset_y: 8.0,
+            ..LayerScroll::default()
+        };
+        assert_eq!(scroll.origin(0.0, 0.0, 100.0, 40.0), (-46.0, 8.0));
+    }
+
+    #[test]
+    fn wrapped_regions_fill_the_clip_window() {
+        assert_eq!(wrap_origins(-300.0, 256.0, false, 0.0, 128.0), vec![-300.0]);
+        assert_eq!(wrap_origins(-300.0, 256.0, true, 0.0, 128.0), vec![-44.0]);
+        assert_eq!(wrap_origins(100.0, 64.0, true, 0.0, 128.0), vec![-28.0, 36.0, 100.0]);
+        assert_eq!(wrap_origins(0.0, 128.0, true, 0.0, 128.0), vec![0.0]);
+        assert_eq!(wrap_origins(50.0, 0.0, true, 0.0, 128.0), vec![50.0]);
+    }
+
+    #[test]
+    fn animations_cycle_through_their_frames() {
+        let mut animations = TileAnimations::default();
+        animations.set(
+            16,
+            vec![
+                TileFrame { sprite: 16, duration: 200 },
+                TileFrame { sprite: 48, duration: 100 },
+            ],
+        );
+
+        assert_eq!(animations.resolve(16, 0), 16);
+        assert_eq!(animations.resolve(16, 199), 16);
+        assert_eq!(animations.resolve(16, 200), 48);
+        assert_eq!(animations.resolve(16, 299), 48);
+        assert_eq!(animations.resolve(16, 300), 16);
+        assert_eq!(animations.resolve(16, 3250), 48);
+        assert_eq!(animations.resolve(17, 250), 17);
+
+        animations.set(16, Vec::new());
+        assert!(!animations.is_animated(16));
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let mut map = TileMap::new(16, 16, 4);
+        map.cells[17] = 3;
+        let path = std::env::temp_dir().join(format!("bbmicro_test_{}.bbmap", std::process::id()));
+        map.save(&path).unwrap();
+        let loaded = TileMap::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, map);
+    }
+
+    #[test]
+    fn empty_map_is_compact() {
+        let mut bytes = Vec::new();
+        TileMap::new(256, 256, 4).write(&mut bytes).unwrap();
+        assert!(bytes.len() < 64, "{} bytes", bytes.len());
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(TileMap::read(&mut &b"NOTAMAP"[..]).is_err());
+
+        let mut bytes = Vec::new();
+        TileMap::new(4, 4, 1).write(&mut bytes).unwrap();
+        bytes[5] = 0xff;
+        assert!(TileMap::read(&mut &bytes[..]).is_err());
+
+        let mut bytes = Vec::new();
+        TileMap::new(4, 4, 1).write(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        assert!(TileMap::read(&mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        let header = |width: u32, height: u32, layers: u8| {
+            let mut bytes = MAP_MAGIC.to_vec();
+            bytes.extend_from_slice(&MAP_VERSION.to_le_bytes());
+            bytes.extend_from_slice(&width.to_le_bytes());
+            bytes.extend_from_slice(&height.to_le_bytes());
+            bytes.push(layers);
+            bytes.resize(bytes.len() + layers as usize, 0);
+            bytes
+        };
+        for &(width, height, layers) in &[(0, 4, 1), (4, 0, 1), (4, 4, 0), (u32::MAX, u32::MAX, 255), (65536, 65536, 1)] {
+            let err = TileMap::read(&mut &header(width, height, layers)[..]).err().unwrap();
+            assert!(err.to_string().starts_with("A "), "{}", err);
+        }
+
+        assert_eq!(TileMap::check_size(1024, 32, 8), Ok(1024 * 32 * 8));
+        assert_eq!(TileMap::check_size(8192, 8192, 1), Ok(MAP_MAX_CELLS));
+        assert!(TileMap::check_size(8192, 8192, 2).is_err());
+        assert!(TileMap::check_size(1, 1, 256).is_err());
+    }
+
+    #[test]
+    fn rejects_runs_past_the_layer() {
+        let mut bytes = Vec::new();
+        TileMap::new(2, 2, 1).write(&mut bytes).unwrap();
+        // The single run of 4 zeros becomes a run of 5.
+        let run = bytes.len() - 2;
+        bytes[run] = 5;
+        assert!(TileMap::read(&mut &bytes[..]).is_err());
+
+        // A run far bigger than the layer doesn't wrap around.
+        let mut bytes = bytes[..run].to_vec();
+        bytes.extend_from_slice(&[0xff; 9]);
+        bytes.extend_from_slice(&[0x01, 0]);
+        assert!(TileMap::read(&mut &bytes[..]).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::audio::SynthBank;
+use crate::sequencer;
+use crate::synth;
+
+/*
+Renders synth sfx and music patterns to WAV files, to listen to them outside
+the game:
+
+    bbmicro wav <project|cart> sfx <name> <out.wav> [--rate 44100] [--loops 1]
+    bbmicro wav <project|cart> music <name> <out.wav> [--rate 44100] [--loops 1]
+
+Sounds are named as for sfx() and music(), by their name in the audio config
+or their number. A sound that loops is written as its intro followed by the
+loop `loops` times. Files are mono 16-bit PCM at up to 192000 Hz, and the
+same sound at the same rate always gives the same bytes.
+*/
+
+pub const DEFAULT_RATE: u32 = 44_100;
+pub const MAX_RATE: u32 = 192_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundKind {
+    Sfx,
+    Music,
+}
+
+impl SoundKind {
+    pub fn parse(kind: &str) -> Option<SoundKind> {
+        match kind {
+            "sfx" => Some(SoundKind::Sfx),
+            "music" => Some(SoundKind::Music),
+            _ => None,
+        }
+    }
+}
+
+// The samples of a synth sound, its loop repeated `loops` times.
+pub fn render(synth: &SynthBank, kind: SoundKind, name: &str, sample_rate: u32, loops: usize) -> Result<Vec<f32>, String> {
+    let rendered = match kind {
+        SoundKind::Sfx => {
+            let n = synth.sfx_number(name).ok_or_else(|| format!("No sfx named {}", name))?;
+            synth::render_sfx(&synth.sfx, n, sample_rate, 0, None)
+        }
+        SoundKind::Music => {
+            let n = synth.pattern_number(name).ok_or_else(|| format!("No music pattern named {}", name))?;
+            sequencer::render_music(&synth.sfx, &synth.patterns, n, sample_rate)
+        }
+    };
+    let mut samples = rendered.intro;
+    for _ in 0..loops {
+        samples.extend_from_slice(&rendered.looped);
+    }
+    Ok(samples)
+}
+
+// Writes mono samples as a 16-bit PCM WAV. Fails if the sizes don't fit
+// the header's 32-bit fields.
+pub fn write_wav<W: Write>(writer: &mut W, samples: &[f32], sample_rate: u32) -> io::Result<()> {
+    let too_big = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is too big for a WAV file", what));
+    let data_bytes = u32::try_from(samples.len())
+        .ok()
+        .and_then(|len| len.checked_mul(2))
+        .ok_or_else(|| too_big("The sound"))?;
+    let riff_bytes = data_bytes.checked_add(36).ok_or_else(|| too_big("The sound"))?;
+    let byte_rate = sample_rate.checked_mul(2).ok_or_else(|| too_big("The sample rate"))?;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&riff_bytes.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM, one channel.
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    // Bytes a second, bytes a frame and bits a sample.
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_bytes.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Renders a synth sound to a WAV file.
+pub fn export<P: AsRef<Path>>(
+    synth: &SynthBank,
+    kind: SoundKind,
+    name: &str,
+    sample_rate: u32,
+    loops: usize,
+    path: P,
+) -> Result<(), Box<dyn Error>> {
+    if sample_rate == 0 {
+        return Err("The sample rate must be above 0".into());
+    }
+    if sample_rate > MAX_RATE {
+        return Err(format!("The sample rate must be at most {}", MAX_RATE).into());
+    }
+    let samples = render(synth, kind, name, sample_rate, loops)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_wav(&mut writer, &samples, sample_rate)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p8::{P8Music, P8Sfx};
+
+    fn bank() -> SynthBank {
+        let mut bank = SynthBank::new();
+        // Four notes, looping over the last two.
+        let line = format!("00040204{:0<160}", "1b0501d0501f05022050");
+        bank.sfx[0] = P8Sfx::from_line(&line).unwrap();
+        bank.sfx_names.insert("jingle".to_string(), 0);
+        bank.patterns[0] = P8Music::from_line("04 00414243").unwrap();
+        bank
+    }
+
+    #[test]
+    fn writes_a_pcm_header() {
+        let mut bytes = Vec::new();
+        write_wav(&mut bytes, &[0.0, 1.0, -1.0], 22050).unwrap();
+
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]), 42);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]), 22050);
+        assert_eq!(u16::from_le_bytes([bytes[34], bytes[35]]), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(&bytes[44..], &[0, 0, 0xff, 0x7f, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn rejects_sizes_the_header_cannot_hold() {
+        let mut bytes = Vec::new();
+        assert!(write_wav(&mut bytes, &[0.0], u32::MAX).is_err());
+        assert!(bytes.is_empty());
+
+        let bank = bank();
+        let path = std::env::temp_dir().join("bbmicro-wav-rate-test.wav");
+        assert!(export(&bank, SoundKind::Sfx, "jingle", MAX_RATE + 1, 1, &path).is_err());
+        assert!(export(&bank, SoundKind::Sfx, "jingle", 0, 1, &path).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn renders_the_same_bytes_every_time() {
+        let bank = bank();
+        let wav = |kind: SoundKind, name: &str, rate: u32| {
+            let mut bytes = Vec::new();
+            write_wav(&mut bytes, &render(&bank, kind, name, rate, 1).unwrap(), rate).unwrap();
+            bytes
+        };
+        assert_eq!(wav(SoundKind::Sfx, "jingle", 44100), wav(SoundKind::Sfx, "0", 44100));
+        assert_eq!(wav(SoundKind::Music, "0", 8000), wav(SoundKind::Music, "0", 8000));
+        assert_ne!(wav(SoundKind::Sfx, "jingle", 44100), wav(SoundKind::Sfx, "jingle", 22050));
+    }
+
+    #[test]
+    fn loops_repeat() {
+        let bank = bank();
+        let rendered = synth::render_sfx(&bank.sfx, 0, 8000, 0, None);
+        assert!(!rendered.looped.is_empty());
+        let once = render(&bank, SoundKind::Sfx, "jingle", 8000, 1).unwrap();
+        let twice = render(&bank, SoundKind::Sfx, "jingle", 8000, 2).unwrap();
+        assert_eq!(once.len(), rendered.intro.len() + rendered.looped.len());
+        assert_eq!(twice.len(), once.len() + rendered.looped.len());
+        assert_eq!(twice[once.len()..], rendered.looped[..]);
+        assert!(render(&bank, SoundKind::Music, "9", 8000, 1).unwrap().is_empty());
+        assert!(render(&bank, SoundKind::Sfx, "boom", 8000, 1).is_err());
+    }
+}